@@ -1,74 +1,659 @@
+use crate::complete::{candidates, Completion};
+use crate::connection::{Connection, Incoming, Link};
+use crate::login::LoginForm;
+use crate::time_format::TimeFormat;
 use crate::MAX_LENGTH;
 use anyhow::Result;
+use chat_proto::{join_lines, FrameError, Message, Timestamp};
 use clap::Parser;
-use std::io::prelude::*;
+use std::{path::PathBuf, time::Duration};
+use tokio::{task::JoinHandle, time::Instant};
+use unicode_segmentation::UnicodeSegmentation;
+
+/// First wait before reconnecting, doubled after every failed attempt.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// What the server accepts in front of `username:password` when logging in.
+const LOGIN_VERBS: [&str; 3] = ["register ", "kick ", "resume "];
+/// Sent lines kept for Up and Down.
+const HISTORY_LEN: usize = 100;
 
 pub struct App {
+    pub screen: Screen,
     pub input: String,
+    /// Counted in grapheme clusters, not bytes.
     pub cursor_position: usize,
-    pub messages: Vec<String>,
-    pub server_socket: std::net::TcpStream,
+    /// The last text cut with Ctrl+W, Ctrl+U or Ctrl+K, put back with Ctrl+Y.
+    pub yank: String,
+    /// Lines sent so far, oldest first.
+    pub history: Vec<String>,
+    /// Which of `history` is in the input while walking it with Up and Down.
+    history_index: Option<usize>,
+    /// What was typed before walking the history, brought back by going past its end.
+    draft: String,
+    pub messages: Vec<Message>,
+    /// How many of `messages` are shown while reading back, `None` follows the newest.
+    pub scroll: Option<usize>,
+    /// How many messages fit in the chat pane last time it was drawn, what PageUp scrolls by.
+    pub page: usize,
+    /// Set once the server puts us in a room.
+    pub room: Option<String>,
+    /// Everyone online, as last pushed by the server.
+    pub roster: Vec<String>,
+    pub show_roster: bool,
+    /// `None` while reconnecting, or before logging in after being logged out.
+    pub link: Option<Link>,
+    /// The connect under way, if any.
+    connecting: Option<Attempt>,
     pub should_quit: bool,
+    /// Where to reconnect to, without it a lost connection ends the app.
+    pub server: Option<Server>,
+    pub status: Status,
+    pub time_format: TimeFormat,
+    /// Set while TAB is cycling through the candidates for a word.
+    pub completion: Option<Completion>,
+    /// The last line sent before logging in, most likely the credentials.
+    login_line: Option<String>,
+    /// Credentials of the last successful login, used to resume after reconnecting.
+    credentials: Option<String>,
+    logged_in: bool,
+}
+
+/// A connect in a task of its own, so that neither the UI waits for it nor `get_messages`
+/// being cancelled cancels it.
+struct Attempt {
+    task: JoinHandle<Result<Connection>>,
+    /// Where the login form asked to go and the line to log in with once there; `None` for
+    /// reconnecting.
+    login: Option<(Server, String)>,
+}
+
+impl Attempt {
+    fn start(server: Server, login: Option<String>) -> Self {
+        let target = server.clone();
+        Self {
+            task: tokio::spawn(async move { Connection::connect(&target).await }),
+            login: login.map(|line| (server, line)),
+        }
+    }
+}
+
+/// What the UI shows: the login form until the server lets us in, the chat after that.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Screen {
+    Login(LoginForm),
+    Chat,
+}
+
+/// How the link to the server is doing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    Connected,
+    /// Lost, `attempt` tries so far, the next one at `retry_at`.
+    Reconnecting {
+        attempt: u32,
+        retry_at: Instant,
+    },
 }
 
-#[derive(Parser)]
+#[derive(Parser, Clone)]
 pub struct Server {
     #[clap(default_value = "0.0.0.0")]
     pub ip: String,
     #[clap(default_value = "8080")]
     pub port: u16,
+    /// Connect over TLS, trusting the usual web certificate authorities
+    #[clap(long)]
+    pub tls: bool,
+    /// Connect over TLS, trusting the certificate authorities in this PEM file instead
+    #[clap(long, value_name = "FILE")]
+    pub ca: Option<PathBuf>,
+    /// Connect over TLS, trusting only the certificate with this SHA-256 fingerprint
+    #[clap(long, value_name = "SHA256")]
+    pub fingerprint: Option<String>,
+    /// Name the server's certificate is checked against, defaults to `ip`
+    #[clap(long, value_name = "NAME")]
+    pub server_name: Option<String>,
+}
+
+impl Default for App {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl App {
+    /// Not connected yet, submitting the login form connects.
+    pub fn new() -> Self {
+        Self {
+            screen: Screen::Login(LoginForm::default()),
+            input: String::new(),
+            cursor_position: 0,
+            yank: String::new(),
+            history: vec![],
+            history_index: None,
+            draft: String::new(),
+            messages: vec![],
+            scroll: None,
+            page: 1,
+            room: None,
+            roster: vec![],
+            show_roster: true,
+            link: None,
+            connecting: None,
+            should_quit: false,
+            server: None,
+            status: Status::Connected,
+            time_format: TimeFormat::default(),
+            completion: None,
+            login_line: None,
+            credentials: None,
+            logged_in: false,
+        }
+    }
+    /// Talks to the server over `connection` from now on. Has to be called inside a tokio
+    /// runtime, the connection gets tasks of its own.
+    pub fn connected(&mut self, connection: impl Into<Connection>) {
+        if let Some(attempt) = self.connecting.take() {
+            attempt.task.abort();
+        }
+        self.link = Some(Link::new(connection.into()));
+        self.status = Status::Connected;
+    }
+
     pub fn move_cursor_left(&mut self) {
         if self.cursor_position > 0 {
             self.cursor_position -= 1;
         }
     }
     pub fn move_cursor_right(&mut self) {
-        if self.cursor_position < self.input.len() {
+        if self.cursor_position < self.input.graphemes(true).count() {
             self.cursor_position += 1;
         }
     }
+    pub fn move_cursor_start(&mut self) {
+        self.cursor_position = 0;
+    }
+    pub fn move_cursor_end(&mut self) {
+        self.cursor_position = self.input.graphemes(true).count();
+    }
+    /// To the start of this word, or the previous one if already there.
+    pub fn move_word_left(&mut self) {
+        self.cursor_position = self.word_start();
+    }
+    /// To the end of this word, or the next one if already there.
+    pub fn move_word_right(&mut self) {
+        let graphemes: Vec<&str> = self.input.graphemes(true).collect();
+        let mut i = self.cursor_position;
+        while i < graphemes.len() && is_space(graphemes[i]) {
+            i += 1;
+        }
+        while i < graphemes.len() && !is_space(graphemes[i]) {
+            i += 1;
+        }
+        self.cursor_position = i;
+    }
+    /// Where `move_word_left` goes, in graphemes.
+    fn word_start(&self) -> usize {
+        let graphemes: Vec<&str> = self.input.graphemes(true).collect();
+        let mut i = self.cursor_position;
+        while i > 0 && is_space(graphemes[i - 1]) {
+            i -= 1;
+        }
+        while i > 0 && !is_space(graphemes[i - 1]) {
+            i -= 1;
+        }
+        i
+    }
+    /// Byte offset of the grapheme the cursor is on.
+    fn cursor_byte_index(&self) -> usize {
+        self.byte_index(self.cursor_position)
+    }
+    /// Byte offset of grapheme number `grapheme`, the end of the input past the last one.
+    fn byte_index(&self, grapheme: usize) -> usize {
+        self.input
+            .grapheme_indices(true)
+            .nth(grapheme)
+            .map_or(self.input.len(), |(i, _)| i)
+    }
     pub fn add_char(&mut self, c: char) {
-        if self.input.len() >= MAX_LENGTH {
+        // the limit is in bytes, that's what the server enforces
+        if sent_length(&self.input) + sent_length(c.encode_utf8(&mut [0; 4])) > MAX_LENGTH {
             return;
         }
-        self.input.insert(self.cursor_position, c);
-        self.move_cursor_right();
+        let i = self.cursor_byte_index();
+        self.input.insert(i, c);
+        // a combining mark joins the previous grapheme instead of adding one
+        self.cursor_position = self.input[..i + c.len_utf8()].graphemes(true).count();
     }
     pub fn remove_char(&mut self) {
         if self.cursor_position > 0 {
+            let end = self.cursor_byte_index();
             self.cursor_position -= 1;
-            self.input.remove(self.cursor_position);
+            let start = self.cursor_byte_index();
+            self.input.replace_range(start..end, "");
         }
     }
+    /// Completes the word before the cursor, or swaps in the next (or, going back, the
+    /// previous) candidate if it was just completed.
+    pub fn complete(&mut self, forward: bool) {
+        let completion = match self.completion.take() {
+            Some(mut c) => {
+                let n = c.candidates.len();
+                let step = if forward { 1 } else { n - 1 };
+                c.index = (c.index + step) % n;
+                c
+            }
+            None => {
+                let end = self.cursor_byte_index();
+                let start = self.input[..end].rfind(' ').map_or(0, |i| i + 1);
+                let found = candidates(&self.input[start..end], start == 0, &self.known_users());
+                if found.is_empty() {
+                    return;
+                }
+                Completion {
+                    start,
+                    end,
+                    index: if forward { 0 } else { found.len() - 1 },
+                    candidates: found,
+                }
+            }
+        };
+        let candidate = &completion.candidates[completion.index];
+        let len = sent_length(&self.input)
+            - sent_length(&self.input[completion.start..completion.end])
+            + sent_length(candidate);
+        if len > MAX_LENGTH {
+            return;
+        }
+        self.input
+            .replace_range(completion.start..completion.end, candidate);
+        let end = completion.start + candidate.len();
+        self.cursor_position = self.input[..end].graphemes(true).count();
+        self.completion = Some(Completion { end, ..completion });
+    }
+
+    /// Everyone online or heard from, sorted.
+    pub fn known_users(&self) -> Vec<String> {
+        let mut users = self.roster.clone();
+        for message in &self.messages {
+            match message {
+                Message::Chat { from, .. }
+                | Message::Action { from, .. }
+                | Message::Direct { from, .. }
+                | Message::Join { user: from, .. }
+                | Message::Leave { user: from, .. } => users.push(from.clone()),
+                _ => {}
+            }
+        }
+        users.sort();
+        users.dedup();
+        users
+    }
+    /// Moves the view `n` messages back, always keeping the oldest one in sight.
+    pub fn scroll_up(&mut self, n: usize) {
+        let end = self.scroll.unwrap_or(self.messages.len());
+        let end = end.saturating_sub(n).max(1);
+        if end < self.messages.len() {
+            self.scroll = Some(end);
+        }
+    }
+    /// Moves the view `n` messages forward, following the newest again once it's reached.
+    pub fn scroll_down(&mut self, n: usize) {
+        self.scroll = self
+            .scroll
+            .map(|end| end + n)
+            .filter(|end| *end < self.messages.len());
+    }
+    /// Messages below the view, that came in while reading back.
+    pub fn unread(&self) -> usize {
+        self.scroll.map_or(0, |end| self.messages.len() - end)
+    }
+    /// Removes the grapheme under the cursor.
+    pub fn delete_char(&mut self) {
+        let start = self.cursor_byte_index();
+        let end = self.byte_index(self.cursor_position + 1);
+        self.input.replace_range(start..end, "");
+    }
+    /// Cuts from the start of the word before the cursor up to it.
+    pub fn kill_word(&mut self) {
+        self.kill(self.word_start(), self.cursor_position);
+    }
+    /// Cuts everything before the cursor.
+    pub fn kill_to_start(&mut self) {
+        self.kill(0, self.cursor_position);
+    }
+    /// Cuts everything after the cursor.
+    pub fn kill_to_end(&mut self) {
+        self.kill(self.cursor_position, self.input.graphemes(true).count());
+    }
+    /// Moves graphemes `start..end` of the input to `yank`, unless there's nothing to cut.
+    fn kill(&mut self, start: usize, end: usize) {
+        if start == end {
+            return;
+        }
+        let range = self.byte_index(start)..self.byte_index(end);
+        self.yank = self.input[range.clone()].to_string();
+        self.input.replace_range(range, "");
+        self.cursor_position = start;
+    }
+    /// Puts the last cut text back at the cursor, if it still fits.
+    pub fn yank(&mut self) {
+        if sent_length(&self.input) + sent_length(&self.yank) > MAX_LENGTH {
+            return;
+        }
+        let i = self.cursor_byte_index();
+        self.input.insert_str(i, &self.yank);
+        self.cursor_position = self.input[..i + self.yank.len()].graphemes(true).count();
+    }
+
+    /// Replaces the input with the line sent before the one shown.
+    pub fn history_previous(&mut self) {
+        let index = match self.history_index {
+            Some(0) => return,
+            Some(i) => i - 1,
+            None if self.history.is_empty() => return,
+            None => {
+                self.draft = self.input.clone();
+                self.history.len() - 1
+            }
+        };
+        self.history_index = Some(index);
+        self.input = self.history[index].clone();
+        self.move_cursor_end();
+    }
+    /// Replaces the input with the line sent after the one shown, or what was being typed.
+    pub fn history_next(&mut self) {
+        let Some(i) = self.history_index else { return };
+        if i + 1 < self.history.len() {
+            self.history_index = Some(i + 1);
+            self.input = self.history[i + 1].clone();
+        } else {
+            self.history_index = None;
+            self.input = std::mem::take(&mut self.draft);
+        }
+        self.move_cursor_end();
+    }
     pub fn reset_cursor(&mut self) {
         self.cursor_position = 0;
     }
-    pub fn submit_message(&mut self) -> Result<()> {
-        if !self.input.is_empty() {
-            self.server_socket.write_all(self.input.as_bytes())?;
+    /// Sends the input to the server, `false` if it couldn't and the input should stay.
+    pub fn submit_message(&mut self) -> bool {
+        if self.input.is_empty() {
+            return true;
         }
-        Ok(())
+        let line = self.input.clone();
+        if !self.send_line(&join_lines(&line)) {
+            return false;
+        }
+        self.history_index = None;
+        if self.history.last() != Some(&line) {
+            self.history.push(line);
+        }
+        if self.history.len() > HISTORY_LEN {
+            self.history.remove(0);
+        }
+        true
+    }
+
+    /// Sends the login form, connecting first if the address changed or the link is down.
+    /// The connect happens in the background, `get_messages` logs in once it's through.
+    pub fn submit_login(&mut self) {
+        let Screen::Login(form) = self.screen.clone() else {
+            return;
+        };
+        if self.connecting_to_log_in() {
+            return;
+        }
+        if form.username.is_empty() || form.password.is_empty() {
+            return self.local_error("Enter both a username and a password");
+        }
+        if let Some(server) = &self.server {
+            let Some((host, port)) = form.host_and_port() else {
+                return self.local_error("The server address has to look like ip:port");
+            };
+            if server.ip != host || server.port != port || self.link.is_none() {
+                let server = Server {
+                    ip: host.to_string(),
+                    port,
+                    ..server.clone()
+                };
+                // the form's address wins over reconnecting to the old one
+                if let Some(attempt) = self.connecting.take() {
+                    attempt.task.abort();
+                }
+                self.connecting = Some(Attempt::start(server, Some(form.line())));
+                return;
+            }
+        }
+        self.send_login(self.messages.len(), &form.line());
+    }
+
+    /// Sends `line` from the login form, the server's answer coming after message `sent_at`.
+    fn send_login(&mut self, sent_at: usize, line: &str) {
+        if let Screen::Login(form) = &mut self.screen {
+            form.sent_at = Some(sent_at);
+        }
+        self.send_line(line);
+    }
+
+    /// Logs in over the connection the login form asked for, or says why there's none.
+    fn login_connected(&mut self, server: Server, line: &str, connection: Result<Connection>) {
+        let connection = match connection {
+            Ok(connection) => connection,
+            Err(e) => {
+                let address = format!("{}:{}", server.ip, server.port);
+                return self.local_error(&format!("Can't connect to {address}: {e}"));
+            }
+        };
+        self.connected(connection);
+        self.server = Some(server);
+        // a new connection greets us with the login prompt first
+        self.send_login(self.messages.len() + 1, line);
+    }
+
+    fn send_line(&mut self, line: &str) -> bool {
+        let Some(link) = &self.link else {
+            self.local_error("Not connected, wait for the connection to come back");
+            return false;
+        };
+        link.send(line);
+        if !self.logged_in {
+            self.login_line = Some(line.to_string());
+        }
+        true
+    }
+
+    fn local_error(&mut self, text: &str) {
+        self.messages.push(Message::Error {
+            time: now(),
+            text: text.to_string(),
+        });
     }
     pub fn clear_input(&mut self) {
         self.input.clear();
     }
-    pub fn get_messages(&mut self) -> Result<()> {
-        let mut buffer = [0; MAX_LENGTH + 24]; // +24 for username and timestamp
-        match self.server_socket.read(&mut buffer) {
-            Ok(n) => {
-                for message in String::from_utf8_lossy(&buffer[..n]).split('\n') {
-                    if !message.is_empty() {
-                        self.messages.push(message.to_string());
-                    }
+
+    /// Waits for the network (a frame, a lost connection, a connect finishing or a reconnect
+    /// coming due) and handles whatever it was. Cancel safe, so it can sit in a `select!`
+    /// with the keyboard.
+    pub async fn get_messages(&mut self) -> Result<()> {
+        if let (None, Status::Reconnecting { retry_at, .. }) = (&self.connecting, self.status) {
+            tokio::time::sleep_until(retry_at).await;
+            let Some(server) = self.server.clone() else {
+                return std::future::pending().await;
+            };
+            self.connecting = Some(Attempt::start(server, None));
+        }
+        if let Some(attempt) = &mut self.connecting {
+            // awaiting the handle by reference leaves it in place if this gets cancelled
+            let result = (&mut attempt.task).await;
+            let login = self.connecting.take().and_then(|attempt| attempt.login);
+            let connection = result.unwrap_or_else(|e| Err(e.into()));
+            match (login, self.status) {
+                (Some((server, line)), _) => self.login_connected(server, &line, connection),
+                (None, Status::Reconnecting { attempt, .. }) => {
+                    self.reconnected(attempt, connection)
+                }
+                (None, Status::Connected) => {}
+            }
+            return Ok(());
+        }
+        let Some(link) = &mut self.link else {
+            return std::future::pending().await;
+        };
+        match link.recv().await {
+            Incoming::Frame(frame) => self.received(frame),
+            Incoming::Closed(reason) => return self.disconnected(&reason),
+        }
+        // take every frame that's already there, so a burst is drawn once
+        while let Some(frame) = self.link.as_mut().and_then(Link::try_frame) {
+            self.received(frame);
+        }
+        Ok(())
+    }
+
+    fn received(&mut self, frame: Result<String, FrameError>) {
+        let frame = match frame {
+            Ok(frame) => frame,
+            // frames the server mangled aren't worth killing the UI over
+            Err(e) => return self.local_error(&format!("Message from server dropped: {e}")),
+        };
+        match Message::decode(&frame) {
+            Ok(Message::Roster { users }) => self.roster = users,
+            Ok(Message::History { room, messages }) if !messages.is_empty() => {
+                self.messages.extend(messages);
+                self.messages.push(Message::System {
+                    time: now(),
+                    text: format!("End of earlier messages in #{room}"),
+                });
+            }
+            Ok(Message::History { .. }) => {}
+            Ok(message @ Message::Bye { .. }) => {
+                self.messages.push(message);
+                self.logged_out();
+            }
+            Ok(message) => {
+                if let Message::Room { name, .. } = &message {
+                    self.room = Some(name.clone());
+                    self.logged_in();
                 }
-                assert!(n == 0 || buffer[n - 1] == b'\n');
+                self.messages.push(message)
             }
-            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
-            Err(e) => Result::Err(e)?,
+            Err(e) => self.local_error(&format!("Unreadable message from server: {e}")),
+        }
+    }
+
+    /// Being put in a room means the last login line worked, so it's worth keeping.
+    fn logged_in(&mut self) {
+        if self.logged_in {
+            return;
+        }
+        self.logged_in = true;
+        if let Screen::Login(form) = &self.screen {
+            // the prompt and failed attempts only mattered on the login screen
+            let start = form.sent_at.unwrap_or(0).min(self.messages.len());
+            self.messages.drain(..start);
+            self.scroll = None;
+            self.screen = Screen::Chat;
         }
+        if let Some(line) = self.login_line.take() {
+            let credentials = LOGIN_VERBS
+                .iter()
+                .find_map(|verb| line.strip_prefix(verb))
+                .unwrap_or(&line);
+            self.credentials = Some(credentials.to_string());
+        }
+    }
+
+    /// The server ended the session on purpose (we quit, or logged in elsewhere), so back
+    /// to the login form instead of reconnecting and resuming.
+    fn logged_out(&mut self) {
+        self.link = None;
+        self.logged_in = false;
+        self.login_line = None;
+        self.room = None;
+        self.roster.clear();
+        let mut form = LoginForm::new(
+            self.server
+                .as_ref()
+                .map_or(String::new(), |s| format!("{}:{}", s.ip, s.port)),
+        );
+        if let Some(credentials) = self.credentials.take() {
+            form.username = credentials
+                .split_once(':')
+                .map_or(credentials.clone(), |(uname, _)| uname.to_string());
+        }
+        self.screen = Screen::Login(form);
+    }
+
+    /// Starts reconnecting, or gives up with an error when there's nowhere to reconnect to.
+    fn disconnected(&mut self, reason: &str) -> Result<()> {
+        if self.server.is_none() {
+            anyhow::bail!("disconnected: {reason}");
+        }
+        self.local_error(&format!("Disconnected: {reason}"));
+        self.link = None;
+        self.logged_in = false;
+        self.status = Status::Reconnecting {
+            attempt: 0,
+            retry_at: Instant::now() + backoff(0),
+        };
         Ok(())
     }
+
+    /// Whether a connect is under way right now.
+    pub fn connecting(&self) -> bool {
+        self.connecting.is_some()
+    }
+
+    /// Whether the login form is waiting for its connect to go through.
+    pub fn connecting_to_log_in(&self) -> bool {
+        self.connecting.as_ref().is_some_and(|a| a.login.is_some())
+    }
+
+    /// Schedules the next attempt if `attempt` failed, otherwise logs back in where we
+    /// left off.
+    fn reconnected(&mut self, attempt: u32, connection: Result<Connection>) {
+        let connection = match connection {
+            Ok(connection) => connection,
+            Err(_) => {
+                self.status = Status::Reconnecting {
+                    attempt: attempt + 1,
+                    retry_at: Instant::now() + backoff(attempt + 1),
+                };
+                return;
+            }
+        };
+        self.connected(connection);
+        self.messages.push(Message::System {
+            time: now(),
+            text: "Reconnected".to_string(),
+        });
+        if let Some(credentials) = self.credentials.clone() {
+            // the server replays what we missed since leaving
+            self.send_line(&format!("resume {credentials}"));
+        }
+    }
+}
+
+/// How long to wait before reconnect attempt number `attempt`, counting from 0.
+pub fn backoff(attempt: u32) -> Duration {
+    INITIAL_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(MAX_BACKOFF)
+}
+
+/// How many bytes `text` takes on the wire, where every line break becomes a 3-byte
+/// `LINE_SEPARATOR`.
+pub fn sent_length(text: &str) -> usize {
+    join_lines(text).len()
+}
+
+fn is_space(grapheme: &str) -> bool {
+    grapheme.chars().all(char::is_whitespace)
+}
+
+fn now() -> Timestamp {
+    chrono::Utc::now()
 }
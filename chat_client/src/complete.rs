@@ -0,0 +1,40 @@
+use chat_proto::{CommandInfo, COMMANDS};
+
+/// The commands to show above the input: those starting with what's typed, while the
+/// command name is all there is.
+pub fn palette(input: &str) -> Vec<&'static CommandInfo> {
+    match input.strip_prefix('/') {
+        Some(name) if !name.starts_with('/') && !name.contains(' ') => COMMANDS
+            .iter()
+            .filter(|c| c.name.starts_with(name))
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// What `word` could be completed to: a command if it opens the line with a `/`,
+/// otherwise one of `users` (ignoring case).
+pub fn candidates(word: &str, first: bool, users: &[String]) -> Vec<String> {
+    if first && word.starts_with('/') {
+        return palette(word)
+            .iter()
+            .map(|c| format!("/{} ", c.name))
+            .collect();
+    }
+    let word = word.to_lowercase();
+    users
+        .iter()
+        .filter(|u| u.to_lowercase().starts_with(&word))
+        .map(|u| format!("{u} "))
+        .collect()
+}
+
+/// A word being completed; pressing TAB again swaps in the next candidate.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Completion {
+    /// Byte range of the input the current candidate fills.
+    pub start: usize,
+    pub end: usize,
+    pub candidates: Vec<String>,
+    pub index: usize,
+}
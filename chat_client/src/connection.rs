@@ -0,0 +1,312 @@
+use crate::app::Server;
+use anyhow::{Context, Result};
+use chat_proto::{FrameError, LineCodec, MAX_FRAME_LENGTH};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    pin::Pin,
+    sync::Arc,
+    task::{Context as TaskContext, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::TcpStream,
+    sync::mpsc,
+    task::JoinHandle,
+};
+use tokio_rustls::{client::TlsStream, TlsConnector};
+
+/// Keeps connecting, TLS handshake included, from hanging on a server that doesn't answer.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const READ_BUFFER_LENGTH: usize = 4 * crate::MAX_LENGTH;
+
+/// The link to the server, encrypted or not.
+pub enum Connection {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Connection {
+    /// Connects as `args` say, finishing the TLS handshake (if any) before returning.
+    pub async fn connect(args: &Server) -> Result<Self> {
+        tokio::time::timeout(CONNECT_TIMEOUT, Self::open(args))
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+    }
+
+    async fn open(args: &Server) -> Result<Self> {
+        let socket = TcpStream::connect((args.ip.as_str(), args.port)).await?;
+        let Some(config) = tls_config(args)? else {
+            return Ok(Connection::Plain(socket));
+        };
+        let name = args.server_name.clone().unwrap_or_else(|| args.ip.clone());
+        let name = ServerName::try_from(name).context("invalid server name")?;
+        let tls = TlsConnector::from(Arc::new(config))
+            .connect(name, socket)
+            .await
+            .context("TLS handshake failed")?;
+        Ok(Connection::Tls(Box::new(tls)))
+    }
+}
+
+impl From<TcpStream> for Connection {
+    fn from(socket: TcpStream) -> Self {
+        Connection::Plain(socket)
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Plain(socket) => Pin::new(socket).poll_read(cx, buf),
+            Connection::Tls(tls) => Pin::new(tls).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Plain(socket) => Pin::new(socket).poll_write(cx, buf),
+            Connection::Tls(tls) => Pin::new(tls).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Plain(socket) => Pin::new(socket).poll_flush(cx),
+            Connection::Tls(tls) => Pin::new(tls).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Plain(socket) => Pin::new(socket).poll_shutdown(cx),
+            Connection::Tls(tls) => Pin::new(tls).poll_shutdown(cx),
+        }
+    }
+}
+
+/// What the connection's tasks report back.
+#[derive(Debug, PartialEq)]
+pub enum Incoming {
+    /// One line from the server, not decoded yet, or why it couldn't be read.
+    Frame(Result<String, FrameError>),
+    /// The connection is gone, and why.
+    Closed(String),
+}
+
+/// A connection driven by a reader and a writer task, so waiting on the network never
+/// holds up the UI. Dropping it stops both.
+pub struct Link {
+    outgoing: mpsc::UnboundedSender<Vec<u8>>,
+    incoming: mpsc::UnboundedReceiver<Incoming>,
+    /// Taken off the channel by `try_frame` but not a frame, so left for `recv`.
+    closed: Option<Incoming>,
+    tasks: [JoinHandle<()>; 2],
+}
+
+impl Link {
+    pub fn new(connection: Connection) -> Self {
+        let (mut reader, mut writer) = tokio::io::split(connection);
+        let (incoming_tx, incoming) = mpsc::unbounded_channel();
+        let (outgoing, mut outgoing_rx) = mpsc::unbounded_channel::<Vec<u8>>();
+
+        let tx = incoming_tx.clone();
+        let read = tokio::spawn(async move {
+            let mut codec = LineCodec::new(MAX_FRAME_LENGTH);
+            let mut buffer = [0; READ_BUFFER_LENGTH];
+            let reason = loop {
+                match reader.read(&mut buffer).await {
+                    Ok(0) => break "the server closed the connection".to_string(),
+                    Ok(n) => codec.feed(&buffer[..n]),
+                    Err(e) => break e.to_string(),
+                }
+                while let Some(frame) = codec.next_frame() {
+                    let _ = tx.send(Incoming::Frame(frame));
+                }
+            };
+            let _ = tx.send(Incoming::Closed(reason));
+        });
+        let write = tokio::spawn(async move {
+            while let Some(data) = outgoing_rx.recv().await {
+                let sent = async {
+                    writer.write_all(&data).await?;
+                    writer.flush().await
+                };
+                if let Err(e) = sent.await {
+                    let _ = incoming_tx.send(Incoming::Closed(e.to_string()));
+                    return;
+                }
+            }
+        });
+        Self {
+            outgoing,
+            incoming,
+            closed: None,
+            tasks: [read, write],
+        }
+    }
+
+    /// Queues `line` for the writer task.
+    pub fn send(&self, line: &str) {
+        // a writer that's gone has already reported why through `recv`
+        let _ = self.outgoing.send(LineCodec::encode(line));
+    }
+
+    /// A frame that already arrived, without waiting.
+    pub fn try_frame(&mut self) -> Option<Result<String, FrameError>> {
+        if self.closed.is_some() {
+            return None;
+        }
+        match self.incoming.try_recv().ok()? {
+            Incoming::Frame(frame) => Some(frame),
+            closed => {
+                self.closed = Some(closed);
+                None
+            }
+        }
+    }
+
+    /// The next thing the server said, or why it stopped talking. Cancel safe.
+    pub async fn recv(&mut self) -> Incoming {
+        if let Some(closed) = self.closed.take() {
+            return closed;
+        }
+        self.incoming
+            .recv()
+            .await
+            .unwrap_or_else(|| Incoming::Closed("connection tasks stopped".to_string()))
+    }
+}
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// `None` for plain TCP, otherwise trusting the pinned fingerprint, the given CA or the web PKI.
+fn tls_config(args: &Server) -> Result<Option<ClientConfig>> {
+    if !args.tls && args.ca.is_none() && args.fingerprint.is_none() {
+        return Ok(None);
+    }
+    let provider = Arc::new(ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    let config = if let Some(fingerprint) = &args.fingerprint {
+        let verifier = Fingerprint {
+            sha256: parse_fingerprint(fingerprint)?,
+            provider,
+        };
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth()
+    } else {
+        let mut roots = RootCertStore::empty();
+        match &args.ca {
+            Some(path) => {
+                let mut reader = BufReader::new(
+                    File::open(path).with_context(|| format!("can't open {}", path.display()))?,
+                );
+                for cert in rustls_pemfile::certs(&mut reader) {
+                    roots.add(cert?)?;
+                }
+            }
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+        builder.with_root_certificates(roots).with_no_client_auth()
+    };
+    Ok(Some(config))
+}
+
+/// Reads a SHA-256 fingerprint written as hex, with or without `:` between the bytes.
+pub fn parse_fingerprint(fingerprint: &str) -> Result<Vec<u8>> {
+    let hex: String = fingerprint.chars().filter(|&c| c != ':').collect();
+    if hex.len() != 64 || !hex.is_ascii() {
+        anyhow::bail!("a SHA-256 fingerprint has 64 hex digits");
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).context("invalid hex in fingerprint"))
+        .collect()
+}
+
+/// Trusts exactly the one certificate whose SHA-256 hash is `sha256`, whoever signed it.
+#[derive(Debug)]
+struct Fingerprint {
+    sha256: Vec<u8>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for Fingerprint {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if Sha256::digest(end_entity.as_ref()).as_slice() == self.sha256 {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "server certificate doesn't match the pinned fingerprint".to_string(),
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
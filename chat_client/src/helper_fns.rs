@@ -1,30 +1,51 @@
-use ratatui::style::Color;
-
-use crate::SYSTEM_MSG_PREFIX;
+use crate::time_format::TimeFormat;
+use chat_proto::Message;
+use ratatui::style::{Color, Modifier, Style};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 
+/// Wraps `line` into rows at most `width` columns wide, preferring to break after whitespace.
+///
+/// Works on grapheme clusters and their display width, so wide (CJK, emoji) characters
+/// take two columns and are never cut in half. A newline in `line` always starts a new row.
 pub fn split_line(line: &str, width: usize, input_mode: bool) -> (String, u16) {
-    let mut ret = String::new();
-    let mut line = line.to_string();
-    if line.starts_with(SYSTEM_MSG_PREFIX) {
-        line = line[SYSTEM_MSG_PREFIX.len()..].to_string();
+    if line.contains('\n') {
+        let parts: Vec<&str> = line.split('\n').collect();
+        let mut rows = vec![];
+        let mut lines_used = 0;
+        for (i, part) in parts.iter().enumerate() {
+            // only the last row can have the cursor past its end
+            let (wrapped, used) = split_line(part, width, input_mode && i == parts.len() - 1);
+            rows.push(wrapped);
+            lines_used += used;
+        }
+        return (rows.join("\n"), lines_used);
     }
+    let mut ret = String::new();
+    let mut graphemes: Vec<(&str, usize)> = line.graphemes(true).map(|g| (g, g.width())).collect();
     let mut lines_used = 1;
-    while line.len() > width {
-        let mut i = width;
-        let ibeg = i;
-        while i >= 1 && !line.chars().nth(i - 1).unwrap().is_whitespace() {
+    while graphemes.iter().map(|(_, w)| w).sum::<usize>() > width {
+        let mut fits = 0;
+        let mut used = 0;
+        while used + graphemes[fits].1 <= width {
+            used += graphemes[fits].1;
+            fits += 1;
+        }
+        let mut i = fits;
+        while i >= 1 && !graphemes[i - 1].0.chars().all(char::is_whitespace) {
             i -= 1;
         }
         if i == 0 {
-            i = ibeg;
+            // no whitespace to break on, so cut the word (but always make progress)
+            i = fits.max(1);
         }
-        ret.push_str(&line[..i]);
+        ret.extend(graphemes.drain(..i).map(|(g, _)| g));
         ret.push('\n');
         lines_used += 1;
-        line = line[i..].to_string();
     }
-    ret.push_str(&line);
-    if line.len() == width && input_mode {
+    let last_width: usize = graphemes.iter().map(|(_, w)| w).sum();
+    ret.extend(graphemes.iter().map(|(g, _)| *g));
+    if last_width == width && input_mode {
         lines_used += 1;
     }
     (ret, lines_used)
@@ -48,3 +69,67 @@ pub fn gen_color(uname: String) -> Color {
     ];
     colors[(hash % colors.len() as u64) as usize]
 }
+
+/// `prefix` followed by `text`, with the later lines of `text` lined up under its first.
+fn indented(prefix: String, text: &str) -> String {
+    let indent = format!("\n{}", " ".repeat(prefix.width()));
+    prefix + &text.replace('\n', &indent)
+}
+
+/// Text and style a message is shown with in the chat pane.
+pub fn display_message(msg: &Message, times: &TimeFormat) -> (String, Style) {
+    let fg = |color| Style::default().fg(color);
+    let stamp = |time| times.time(time);
+    match msg {
+        Message::System { time, text } | Message::Bye { time, text } => (
+            indented(format!("[{}] ", stamp(time)), text),
+            fg(Color::LightYellow),
+        ),
+        Message::Error { time, text } => (
+            indented(format!("[{}] ", stamp(time)), text),
+            fg(Color::LightRed),
+        ),
+        Message::Join { time, user, room } => (
+            format!("[{}] {user} joined #{room}", stamp(time)),
+            fg(Color::LightYellow),
+        ),
+        Message::Leave { time, user, room } => (
+            format!("[{}] {user} left #{room}", stamp(time)),
+            fg(Color::LightYellow),
+        ),
+        Message::Room { time, name } => (
+            format!("[{}] You are now in #{name}", stamp(time)),
+            fg(Color::LightYellow),
+        ),
+        // normally unpacked into the chat pane instead
+        Message::History { room, messages } => (
+            format!("{} earlier messages in #{room}", messages.len()),
+            fg(Color::LightYellow),
+        ),
+        // normally shown in the sidebar instead
+        Message::Roster { users } => (
+            format!("Online: {}", users.join(", ")),
+            fg(Color::LightYellow),
+        ),
+        Message::Chat { time, from, text } => (
+            indented(format!("[{}] {from}: ", stamp(time)), text),
+            fg(gen_color(from.clone())),
+        ),
+        Message::Action { time, from, text } => (
+            indented(format!("[{}] * {from} ", stamp(time)), text),
+            fg(gen_color(from.clone())).add_modifier(Modifier::ITALIC),
+        ),
+        Message::Direct {
+            time,
+            from,
+            to,
+            text,
+        } => (
+            indented(
+                format!("[{}] {from} -> {to} (private): ", stamp(time)),
+                text,
+            ),
+            fg(gen_color(from.clone())).add_modifier(Modifier::ITALIC | Modifier::REVERSED),
+        ),
+    }
+}
@@ -0,0 +1,101 @@
+use crate::MAX_LENGTH;
+
+/// The form shown before logging in, so the password never ends up in the chat input.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LoginForm {
+    pub username: String,
+    pub password: String,
+    /// `ip:port`, filled in from the command line.
+    pub address: String,
+    pub mode: Mode,
+    pub focus: Field,
+    /// How many messages the app had when the credentials went out; what came before is
+    /// the login chatter, dropped once the server lets us in.
+    pub sent_at: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Field {
+    #[default]
+    Username,
+    Password,
+    Address,
+}
+
+/// What submitting the form asks the server for.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Mode {
+    #[default]
+    Login,
+    Register,
+    /// Logs in even if the account is already online, ending the other session.
+    Kick,
+}
+
+impl LoginForm {
+    pub fn new(address: String) -> Self {
+        Self {
+            address,
+            ..Self::default()
+        }
+    }
+
+    fn field_mut(&mut self) -> &mut String {
+        match self.focus {
+            Field::Username => &mut self.username,
+            Field::Password => &mut self.password,
+            Field::Address => &mut self.address,
+        }
+    }
+
+    pub fn add_char(&mut self, c: char) {
+        let field = self.field_mut();
+        if field.len() + c.len_utf8() <= MAX_LENGTH {
+            field.push(c);
+        }
+    }
+
+    pub fn remove_char(&mut self) {
+        self.field_mut().pop();
+    }
+
+    pub fn next_field(&mut self) {
+        self.focus = match self.focus {
+            Field::Username => Field::Password,
+            Field::Password => Field::Address,
+            Field::Address => Field::Username,
+        };
+    }
+
+    pub fn previous_field(&mut self) {
+        self.focus = match self.focus {
+            Field::Username => Field::Address,
+            Field::Password => Field::Username,
+            Field::Address => Field::Password,
+        };
+    }
+
+    pub fn next_mode(&mut self) {
+        self.mode = match self.mode {
+            Mode::Login => Mode::Register,
+            Mode::Register => Mode::Kick,
+            Mode::Kick => Mode::Login,
+        };
+    }
+
+    /// The line the server expects for this form.
+    pub fn line(&self) -> String {
+        let verb = match self.mode {
+            Mode::Login => "",
+            Mode::Register => "register ",
+            Mode::Kick => "kick ",
+        };
+        format!("{verb}{}:{}", self.username, self.password)
+    }
+
+    /// `address` split into host and port, `None` if it isn't `host:port`.
+    pub fn host_and_port(&self) -> Option<(&str, u16)> {
+        let (host, port) = self.address.trim().rsplit_once(':')?;
+        Some((host, port.parse().ok()?))
+    }
+}
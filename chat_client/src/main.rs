@@ -1,6 +1,10 @@
 pub mod app;
+pub mod complete;
+pub mod connection;
 pub mod helper_fns;
+pub mod login;
 pub mod run;
+pub mod time_format;
 pub mod tui;
 pub mod ui;
 pub mod update;
@@ -9,10 +13,10 @@ use crate::run::run;
 use anyhow::Result;
 
 const MAX_LENGTH: usize = 1000;
-const SYSTEM_MSG_PREFIX: &str = "SYSTEM: ";
 
-fn main() -> Result<()> {
-    let result = run();
+#[tokio::main]
+async fn main() -> Result<()> {
+    let result = run().await;
 
     result?;
 
@@ -22,22 +26,37 @@ fn main() -> Result<()> {
 #[cfg(test)]
 mod tests {
     use crate::app::App;
+    use crate::app::Screen;
     use crate::app::Server;
+    use crate::app::Status;
+    use crate::connection::{parse_fingerprint, Connection};
+    use crate::login::{LoginForm, Mode};
+    use crate::time_format::TimeFormat;
+    use crate::MAX_LENGTH;
+    use chat_proto::{Message, Timestamp};
+    use chat_server::testing::{TestServer, PASSWORD, READ_TIMEOUT, T0};
     use clap::Parser;
-    use std::net::TcpStream;
+    use tokio::net::TcpStream;
     const SAMPLE_TEXT: &str =
         "Lorem ipsum dolor sit amet, consectetur adipiscing elit. Sed non risus. Suspendisse";
 
-    #[test]
-    fn test_invalid_cursor_moves() {
-        let args = Server::parse();
-        let mut app = App {
-            input: SAMPLE_TEXT.to_string(),
-            cursor_position: 0,
-            messages: vec![],
-            server_socket: TcpStream::connect(format!("{}:{}", args.ip, args.port)).unwrap(),
-            should_quit: false,
-        };
+    /// An app connected to a listener that never answers, for tests that send; what it
+    /// sends goes through as long as the listener is kept.
+    async fn connected_app() -> (App, std::net::TcpListener) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut app = App::new();
+        app.connected(
+            TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap(),
+        );
+        (app, listener)
+    }
+
+    #[tokio::test]
+    async fn test_invalid_cursor_moves() {
+        let mut app = App::new();
+        app.input = SAMPLE_TEXT.to_string();
         app.move_cursor_left();
         assert_eq!(app.cursor_position, 0);
         for i in 0..app.input.len() {
@@ -48,16 +67,10 @@ mod tests {
         assert_eq!(app.cursor_position, app.input.len());
     }
 
-    #[test]
-    fn test_inserts_and_deletions() {
-        let args = Server::parse();
-        let mut app = App {
-            input: SAMPLE_TEXT.to_string(),
-            cursor_position: 0,
-            messages: vec![],
-            server_socket: TcpStream::connect(format!("{}:{}", args.ip, args.port)).unwrap(),
-            should_quit: false,
-        };
+    #[tokio::test]
+    async fn test_inserts_and_deletions() {
+        let mut app = App::new();
+        app.input = SAMPLE_TEXT.to_string();
         app.add_char('a');
         assert_eq!(app.input, format!("a{}", SAMPLE_TEXT));
         app.remove_char();
@@ -83,34 +96,942 @@ mod tests {
     }
 
     #[test]
-    fn test_messages() {
-        let args = Server::parse();
-        let mut app = App {
-            input: SAMPLE_TEXT.to_string(),
-            cursor_position: 0,
-            messages: vec![],
-            server_socket: TcpStream::connect(format!("{}:{}", args.ip, args.port)).unwrap(),
-            should_quit: false,
-        };
-        app.get_messages().unwrap();
+    fn test_split_line_hard_breaks() {
+        use crate::helper_fns::split_line;
+
+        let (line, lines_used) = split_line("one two three\n\nfour", 8, false);
+        assert_eq!(line, "one two \nthree\n\nfour");
+        assert_eq!(lines_used, 4);
+        // the cursor only needs a row of its own after the last line
+        assert_eq!(split_line("12345678\n1234", 8, true).1, 2);
+        assert_eq!(split_line("1234\n12345678", 8, true).1, 3);
+    }
+
+    #[test]
+    fn test_time_format() {
+        #[derive(Parser)]
+        struct Cli {
+            #[clap(flatten)]
+            time_format: TimeFormat,
+        }
+
+        let time: Timestamp = "2024-03-10T23:05:09Z".parse().unwrap();
+        let default = Cli::parse_from(["chat_client"]).time_format;
+        assert_eq!(default, TimeFormat::default());
+        assert!(!default.utc);
+        let custom = Cli::parse_from([
+            "chat_client",
+            "--utc",
+            "--time-format",
+            "%I:%M:%S %p",
+            "--date-format",
+            "%Y-%m-%d",
+        ])
+        .time_format;
+        assert_eq!(custom.time(&time), "11:05:09 PM");
+        assert_eq!(custom.separator(&time), "── 2024-03-10 ──");
+        assert_eq!(custom.day(&time).to_string(), "2024-03-10");
+        assert!(Cli::try_parse_from(["chat_client", "--time-format", "%H:%Q"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_day_separators() {
+        use ratatui::{backend::TestBackend, Terminal};
+
+        let mut app = App::new();
+        app.screen = Screen::Chat;
+        app.show_roster = false;
+        app.time_format = TimeFormat {
+            utc: true,
+            date: "%Y-%m-%d".to_string(),
+            ..TimeFormat::default()
+        };
+        let at = |time: &str| Message::Chat {
+            time: time.parse().unwrap(),
+            from: "kasia".to_string(),
+            text: "hi".to_string(),
+        };
+        app.messages = vec![
+            at("2024-03-09T22:00:00Z"),
+            at("2024-03-09T23:00:00Z"),
+            at("2024-03-10T00:10:00Z"),
+            chat("kasia", "now"),
+        ];
+        if let Message::Chat { time, .. } = &mut app.messages[3] {
+            *time = chrono::Utc::now();
+        }
+        // the chat pane gets rows 2 to 9, one more than needed
+        let mut terminal = Terminal::new(TestBackend::new(40, 14)).unwrap();
+        terminal.draw(|f| crate::ui::render(&mut app, f)).unwrap();
+        let rows: Vec<String> = terminal
+            .backend()
+            .buffer()
+            .content
+            .chunks(40)
+            .map(|row| row.iter().map(|c| c.symbol()).collect::<String>())
+            .collect();
+        let today = chrono::Utc::now().format("%Y-%m-%d").to_string();
+        let separator = |day: &str| format!("│{:^38}│", format!("── {day} ──"));
+        let message = |text: &str| format!("│{text:<38}│");
         assert_eq!(
-            app.messages[0],
-            "SYSTEM: [00:00] Please enter [username]:[password]"
+            rows[3..8],
+            [
+                separator("2024-03-09"),
+                message("[22:00] kasia: hi"),
+                message("[23:00] kasia: hi"),
+                separator("2024-03-10"),
+                message("[00:10] kasia: hi"),
+            ]
         );
+        assert_eq!(rows[8], separator(&today));
+        assert!(rows[9].contains("] kasia: now"));
+    }
+
+    #[test]
+    fn test_narrow_terminal() {
+        use ratatui::{backend::TestBackend, Terminal};
+
+        let mut app = App::new();
+        app.screen = Screen::Chat;
+        app.roster = vec!["kasia".to_string()];
+        app.messages = vec![chat("kasia", "hi")];
+        app.input = "typed".to_string();
+        let online = |terminal: &Terminal<TestBackend>| {
+            let buffer = terminal.backend().buffer();
+            let text: String = buffer.content.iter().map(|c| c.symbol()).collect();
+            text.contains("Online")
+        };
+        for width in [1, 2, 3, 17, 39] {
+            let mut terminal = Terminal::new(TestBackend::new(width, 10)).unwrap();
+            terminal.draw(|f| crate::ui::render(&mut app, f)).unwrap();
+            assert!(!online(&terminal), "roster shown {width} columns wide");
+        }
+        let mut terminal = Terminal::new(TestBackend::new(40, 10)).unwrap();
+        terminal.draw(|f| crate::ui::render(&mut app, f)).unwrap();
+        assert!(online(&terminal));
+    }
+
+    #[test]
+    fn test_multi_line_display() {
+        let utc = TimeFormat {
+            utc: true,
+            ..TimeFormat::default()
+        };
+        let (text, _) = crate::helper_fns::display_message(&chat("kasia", "first\nsecond"), &utc);
+        assert_eq!(text, "[00:00] kasia: first\n               second");
+    }
+
+    #[tokio::test]
+    async fn test_multi_line_input() {
+        use crate::update::update;
+        use crossterm::event::{Event, KeyCode, KeyEvent, KeyModifiers};
+        use ratatui::{backend::TestBackend, Terminal};
+
+        let (mut app, _listener) = connected_app().await;
+        app.screen = Screen::Chat;
+        let key = |code, modifiers| Event::Key(KeyEvent::new(code, modifiers));
+        for c in "hi".chars() {
+            update(&mut app, key(KeyCode::Char(c), KeyModifiers::NONE)).await;
+        }
+        update(&mut app, key(KeyCode::Enter, KeyModifiers::ALT)).await;
+        update(&mut app, key(KeyCode::Enter, KeyModifiers::SHIFT)).await;
+        update(&mut app, key(KeyCode::Char('x'), KeyModifiers::NONE)).await;
+        assert_eq!(app.input, "hi\n\nx");
+
+        let mut terminal = Terminal::new(TestBackend::new(40, 12)).unwrap();
+        terminal.draw(|f| crate::ui::render(&mut app, f)).unwrap();
+        // the input box is the bottom 5 rows, so its third line is row 10
+        assert_eq!(terminal.get_cursor().unwrap(), (2, 10));
+        app.move_cursor_left();
+        app.move_cursor_left();
+        terminal.draw(|f| crate::ui::render(&mut app, f)).unwrap();
+        assert_eq!(terminal.get_cursor().unwrap(), (1, 9));
+
+        update(&mut app, key(KeyCode::Enter, KeyModifiers::NONE)).await;
+        assert!(app.input.is_empty());
+        assert_eq!(app.history, ["hi\n\nx"]);
+    }
+
+    #[test]
+    fn test_input_limit_counts_sent_line_breaks() {
+        let mut app = App::new();
+        app.input = "a".repeat(MAX_LENGTH - 6);
+        app.move_cursor_end();
+        // each line break goes out as a 3-byte LINE_SEPARATOR
+        app.add_char('\n');
+        app.add_char('\n');
+        assert_eq!(crate::app::sent_length(&app.input), MAX_LENGTH);
+        app.add_char('b');
+        assert_eq!(app.input.len(), MAX_LENGTH - 4);
+
+        app.remove_char();
+        app.yank = "\n\n".to_string();
+        app.yank();
+        assert_eq!(app.input.len(), MAX_LENGTH - 5);
+        app.yank = "bb".to_string();
+        app.yank();
+        assert_eq!(app.input.len(), MAX_LENGTH - 3);
+    }
+
+    #[tokio::test]
+    async fn test_altgr_chars() {
+        use crate::update::update;
+        use crossterm::event::{Event, KeyCode, KeyEvent, KeyModifiers};
+
+        let mut app = App::new();
+        app.screen = Screen::Chat;
+        let altgr = KeyModifiers::CONTROL | KeyModifiers::ALT;
+        for c in "ł@".chars() {
+            update(&mut app, Event::Key(KeyEvent::new(KeyCode::Char(c), altgr))).await;
+        }
+        assert_eq!(app.input, "ł@");
+        // the bindings still win over plain CONTROL or ALT
+        let ctrl_a = KeyEvent::new(KeyCode::Char('a'), KeyModifiers::CONTROL);
+        update(&mut app, Event::Key(ctrl_a)).await;
+        assert_eq!(app.input, "ł@");
+        assert_eq!(app.cursor_position, 0);
+    }
+
+    #[tokio::test]
+    async fn test_utf8_editing() {
+        let mut app = App::new();
+        for c in "zażółć".chars() {
+            app.add_char(c);
+        }
+        assert_eq!(app.cursor_position, 6);
+        app.move_cursor_right();
+        assert_eq!(app.cursor_position, 6);
+        app.move_cursor_left();
+        app.move_cursor_left();
+        app.remove_char();
+        assert_eq!(app.input, "zażłć");
+        app.add_char('ó');
+        assert_eq!(app.input, "zażółć");
+        assert_eq!(app.cursor_position, 4);
+
+        // an emoji family is one grapheme made of five code points
+        app.input = "a👩‍👩‍👧b".to_string();
+        app.cursor_position = 0;
+        app.move_cursor_right();
+        app.move_cursor_right();
+        app.move_cursor_right();
+        app.move_cursor_right();
+        assert_eq!(app.cursor_position, 3);
+        app.move_cursor_left();
+        app.remove_char();
+        assert_eq!(app.input, "ab");
+        assert_eq!(app.cursor_position, 1);
+
+        // a combining accent merges with the letter before it
+        app.add_char('e');
+        app.add_char('\u{301}');
+        assert_eq!(app.input, "ae\u{301}b");
+        assert_eq!(app.cursor_position, 2);
+        app.remove_char();
+        assert_eq!(app.input, "ab");
+    }
+
+    #[test]
+    fn test_split_line_wide_chars() {
+        let (line, lines_used) = crate::helper_fns::split_line("你好世界你好", 5, false);
+        assert_eq!(line, "你好\n世界\n你好");
+        assert_eq!(lines_used, 3);
+
+        let (line, lines_used) = crate::helper_fns::split_line("ok 🦀🦀 zażółć", 6, false);
+        assert_eq!(line, "ok \n🦀🦀 \nzażółć");
+        assert_eq!(lines_used, 3);
+
+        // the cursor needs an extra row once the input fills the last one
+        let (_, lines_used) = crate::helper_fns::split_line("日本", 4, true);
+        assert_eq!(lines_used, 2);
+    }
+
+    /// Polls the server until the message with index `i` has arrived.
+    async fn wait_for_message(app: &mut App, i: usize) {
+        while app.messages.len() <= i {
+            app.get_messages().await.unwrap();
+        }
+    }
+
+    /// Index of the first `msg` received at or after `from`; waits until it arrives.
+    async fn wait_for(app: &mut App, from: usize, msg: &Message) -> usize {
+        loop {
+            if let Some(i) = app.messages.iter().skip(from).position(|m| m == msg) {
+                return from + i;
+            }
+            app.get_messages().await.unwrap();
+        }
+    }
 
-        app.input = "ptr:123456".to_string();
-        app.submit_message().unwrap();
+    /// An app that logged in to `server` as `uname` through the login form.
+    async fn log_in(server: &TestServer, uname: &str, mode: Mode) -> App {
+        let mut app = App::new();
+        app.server = Some(tls_args(server.addr.port()));
+        let mut form = LoginForm::new(server.addr.to_string());
+        form.username = uname.to_string();
+        form.password = PASSWORD.to_string();
+        form.mode = mode;
+        app.screen = Screen::Login(form);
+        app.submit_login();
+        while app.screen != Screen::Chat {
+            app.get_messages().await.unwrap();
+        }
+        app
+    }
 
-        app.get_messages().unwrap();
-        assert_eq!(app.messages[1], "SYSTEM: [00:00] Welcome to the chat!");
+    /// Handles whatever arrives for `time`.
+    async fn idle(app: &mut App, time: std::time::Duration) {
+        let _ = tokio::time::timeout(time, async {
+            loop {
+                app.get_messages().await.unwrap();
+            }
+        })
+        .await;
+    }
 
-        app.get_messages().unwrap();
-        assert_eq!(app.messages[2], "SYSTEM: [00:00] ptr logged in");
+    #[tokio::test]
+    async fn test_kicked_stays_logged_out() {
+        let server = TestServer::start();
+        let mut first = log_in(&server, "ptr", Mode::Login).await;
+        let mut second = log_in(&server, "ptr", Mode::Kick).await;
+        let told = tokio::time::timeout(READ_TIMEOUT, async {
+            while first.screen == Screen::Chat {
+                first.get_messages().await.unwrap();
+            }
+        });
+        told.await.expect("the first app was never logged out");
+        assert!(matches!(first.messages.last(), Some(Message::Bye { .. })));
+        assert!(first.link.is_none());
+        let Screen::Login(form) = &first.screen else {
+            unreachable!()
+        };
+        assert_eq!(form.username, "ptr");
+
+        // long enough for a reconnect, which would kick the second one in turn
+        idle(&mut first, std::time::Duration::from_secs(2)).await;
+        assert_eq!(first.status, Status::Connected);
+        assert!(first.link.is_none());
+        idle(&mut second, std::time::Duration::from_millis(200)).await;
+        assert_eq!(second.screen, Screen::Chat);
+        assert!(second.link.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_quit_logs_out() {
+        let server = TestServer::start();
+        let mut app = log_in(&server, "ptr", Mode::Login).await;
+        app.input = "/quit".to_string();
+        assert!(app.submit_message());
+        let told = tokio::time::timeout(READ_TIMEOUT, async {
+            while app.screen == Screen::Chat {
+                app.get_messages().await.unwrap();
+            }
+        });
+        told.await.expect("/quit didn't log out");
+        assert!(matches!(app.messages.last(), Some(Message::Bye { .. })));
+
+        // no reconnecting and resuming behind our back
+        idle(&mut app, std::time::Duration::from_secs(2)).await;
+        assert!(app.link.is_none());
+        assert!(matches!(app.screen, Screen::Login(_)));
+        assert!(!app.connecting());
+    }
+
+    #[tokio::test]
+    async fn test_messages() {
+        let server = TestServer::start();
+        let mut app = App::new();
+        app.connected(TcpStream::connect(server.addr).await.unwrap());
+        app.input = SAMPLE_TEXT.to_string();
+        wait_for_message(&mut app, 0).await;
+        assert_eq!(
+            app.messages[0],
+            Message::System {
+                time: T0,
+                text: "Please enter [username]:[password] or register [username]:[password]"
+                    .to_string()
+            }
+        );
+
+        let Screen::Login(form) = &mut app.screen else {
+            panic!("the app starts on the login screen");
+        };
+        form.username = "ptr".to_string();
+        form.password = "123456".to_string();
+        app.submit_login();
+        while app.screen != Screen::Chat {
+            app.get_messages().await.unwrap();
+        }
+
+        // the login prompt is gone, the chat starts with the welcome
+        wait_for_message(&mut app, 1).await;
+        assert_eq!(
+            app.messages[0],
+            Message::System {
+                time: T0,
+                text: "Welcome to the chat!".to_string()
+            }
+        );
+        assert_eq!(
+            app.messages[1],
+            Message::Room {
+                time: T0,
+                name: "lobby".to_string()
+            }
+        );
+        assert_eq!(app.room.as_deref(), Some("lobby"));
+        let joined = Message::Join {
+            time: T0,
+            user: "ptr".to_string(),
+            room: "lobby".to_string(),
+        };
+        let i = wait_for(&mut app, 2, &joined).await;
 
         app.input = "Hello there!".to_string();
-        app.submit_message().unwrap();
+        app.submit_message();
+
+        let hello = Message::Chat {
+            time: T0,
+            from: "ptr".to_string(),
+            text: "Hello there!".to_string(),
+        };
+        wait_for(&mut app, i + 1, &hello).await;
+    }
+
+    fn chat(from: &str, text: &str) -> Message {
+        Message::Chat {
+            time: Timestamp::UNIX_EPOCH,
+            from: from.to_string(),
+            text: text.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fragmented_and_batched_frames() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            use std::io::Write;
+            let (mut socket, _) = listener.accept().unwrap();
+            let mut frames: Vec<String> = [chat("a", "first"), chat("b", "second")]
+                .iter()
+                .map(Message::encode)
+                .collect();
+            // too long to take, so it's reported and skipped
+            frames.push("x".repeat(chat_proto::MAX_FRAME_LENGTH + 1));
+            frames.push(chat("c", "third").encode());
+            let wire: String = frames.iter().map(|f| format!("{f}\n")).collect();
+            // cut the stream mid-frame and also put several frames into one write
+            let (first, rest) = wire.split_at(wire.find("second").unwrap());
+            let (second, third) = rest.split_at(rest.find("\"from\":\"c\"").unwrap());
+            for part in [first, second, third] {
+                socket.write_all(part.as_bytes()).unwrap();
+                socket.flush().unwrap();
+                std::thread::sleep(std::time::Duration::from_millis(20));
+            }
+        });
+
+        let mut app = App::new();
+        app.connected(TcpStream::connect(addr).await.unwrap());
+        wait_for_message(&mut app, 3).await;
+        assert_eq!(app.messages[..2], [chat("a", "first"), chat("b", "second")]);
+        assert!(matches!(
+            &app.messages[2],
+            Message::Error { text, .. } if text == "Message from server dropped: frame too long"
+        ));
+        assert_eq!(app.messages[3], chat("c", "third"));
+        server.join().unwrap();
+    }
+
+    /// Serves one TLS connection with `cert`, sending it a single chat message.
+    fn tls_server(cert: &rcgen::CertifiedKey) -> (u16, std::thread::JoinHandle<()>) {
+        use std::io::Write;
+        use std::sync::Arc;
+
+        let key =
+            rustls::pki_types::PrivateKeyDer::try_from(cert.key_pair.serialize_der()).unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.cert.der().clone()], key)
+            .unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            let conn = rustls::ServerConnection::new(Arc::new(config)).unwrap();
+            let mut tls = rustls::StreamOwned::new(conn, socket);
+            // fails when the client walks away during the handshake, which some tests want
+            let _ = tls.write_all(&chat_proto::LineCodec::encode(
+                &chat("a", "secret").encode(),
+            ));
+            let _ = tls.flush();
+        });
+        (port, server)
+    }
+
+    fn tls_args(port: u16) -> Server {
+        Server {
+            ip: "127.0.0.1".to_string(),
+            port,
+            tls: false,
+            ca: None,
+            fingerprint: None,
+            server_name: Some("localhost".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tls_pinned_fingerprint() {
+        use sha2::{Digest, Sha256};
+
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let hash = Sha256::digest(cert.cert.der());
+        let fingerprint: Vec<String> = hash.iter().map(|b| format!("{b:02X}")).collect();
+
+        let (port, server) = tls_server(&cert);
+        let args = Server {
+            fingerprint: Some(fingerprint.join(":")),
+            ..tls_args(port)
+        };
+        let mut app = App::new();
+        app.connected(Connection::connect(&args).await.unwrap());
+        wait_for_message(&mut app, 0).await;
+        assert_eq!(app.messages, vec![chat("a", "secret")]);
+        server.join().unwrap();
+
+        // any other certificate is refused
+        let other = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let (port, server) = tls_server(&other);
+        let args = Server {
+            fingerprint: Some(fingerprint.join(":")),
+            ..tls_args(port)
+        };
+        assert!(Connection::connect(&args).await.is_err());
+        server.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tls_custom_ca() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let ca = std::env::temp_dir().join(format!("chat_ca_{}.pem", std::process::id()));
+        std::fs::write(&ca, cert.cert.pem()).unwrap();
+
+        let (port, server) = tls_server(&cert);
+        let args = Server {
+            ca: Some(ca.clone()),
+            ..tls_args(port)
+        };
+        let mut app = App::new();
+        app.connected(Connection::connect(&args).await.unwrap());
+        wait_for_message(&mut app, 0).await;
+        assert_eq!(app.messages, vec![chat("a", "secret")]);
+        server.join().unwrap();
+
+        // the certificate has to be for the name we connect to
+        let (port, server) = tls_server(&cert);
+        let args = Server {
+            ca: Some(ca.clone()),
+            server_name: Some("example.com".to_string()),
+            ..tls_args(port)
+        };
+        assert!(Connection::connect(&args).await.is_err());
+        server.join().unwrap();
+        std::fs::remove_file(ca).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tls_handshake_times_out() {
+        // takes the connection, then never says a word
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let args = Server {
+            tls: true,
+            ..tls_args(listener.local_addr().unwrap().port())
+        };
+        let start = std::time::Instant::now();
+        assert!(Connection::connect(&args).await.is_err());
+        assert!(start.elapsed() < std::time::Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_login_form() {
+        use crate::login::{Field, LoginForm};
+
+        let mut form = LoginForm::new("127.0.0.1:8080".to_string());
+        for c in "kasia".chars() {
+            form.add_char(c);
+        }
+        form.next_field();
+        assert_eq!(form.focus, Field::Password);
+        for c in "123456x".chars() {
+            form.add_char(c);
+        }
+        form.remove_char();
+        assert_eq!(form.line(), "kasia:123456");
+        form.next_mode();
+        assert_eq!(form.line(), "register kasia:123456");
+        form.next_mode();
+        assert_eq!(form.line(), "kick kasia:123456");
+        form.next_mode();
+        assert_eq!(form.line(), "kasia:123456");
+
+        assert_eq!(form.host_and_port(), Some(("127.0.0.1", 8080)));
+        form.previous_field();
+        form.previous_field();
+        assert_eq!(form.focus, Field::Address);
+        form.remove_char();
+        assert_eq!(form.host_and_port(), Some(("127.0.0.1", 808)));
+        form.address = "localhost".to_string();
+        assert_eq!(form.host_and_port(), None);
+    }
+
+    #[test]
+    fn test_login_form_needs_credentials() {
+        let mut app = App::new();
+        app.submit_login();
+        assert!(matches!(app.messages[..], [Message::Error { .. }]));
+        assert!(matches!(app.screen, Screen::Login(_)));
+    }
+
+    #[tokio::test]
+    async fn test_login_connects_in_background() {
+        use ratatui::{backend::TestBackend, Terminal};
+
+        let nowhere = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = nowhere.local_addr().unwrap().port();
+        drop(nowhere);
+        let mut app = App::new();
+        app.server = Some(tls_args(port));
+        let mut form = LoginForm::new(format!("127.0.0.1:{port}"));
+        form.username = "ptr".to_string();
+        form.password = PASSWORD.to_string();
+        app.screen = Screen::Login(form);
+
+        app.submit_login();
+        assert!(app.connecting_to_log_in());
+        let mut terminal = Terminal::new(TestBackend::new(60, 15)).unwrap();
+        terminal.draw(|f| crate::ui::render(&mut app, f)).unwrap();
+        let text: String = terminal
+            .backend()
+            .buffer()
+            .content
+            .iter()
+            .map(|c| c.symbol())
+            .collect();
+        assert!(text.contains("Log in (connecting…)"));
+
+        app.get_messages().await.unwrap();
+        assert!(!app.connecting());
+        let Some(Message::Error { text, .. }) = app.messages.last() else {
+            panic!("no error after failing to connect");
+        };
+        assert!(text.starts_with(&format!("Can't connect to 127.0.0.1:{port}")));
+        assert!(matches!(app.screen, Screen::Login(_)));
+    }
+
+    #[tokio::test]
+    async fn test_login_connects() {
+        let server = TestServer::start();
+        // nothing listens where the command line pointed, the form says where to go instead
+        let nowhere = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut app = App::new();
+        app.server = Some(Server {
+            port: nowhere.local_addr().unwrap().port(),
+            ..tls_args(0)
+        });
+        drop(nowhere);
+        let mut form = crate::login::LoginForm::new(server.addr.to_string());
+        form.username = "ptr".to_string();
+        form.password = "123456".to_string();
+        app.screen = Screen::Login(form);
+
+        app.submit_login();
+        while app.screen != Screen::Chat {
+            app.get_messages().await.unwrap();
+        }
+        // the new connection's login prompt went with the login screen
+        assert_eq!(
+            app.messages[..2],
+            [
+                Message::System {
+                    time: T0,
+                    text: "Welcome to the chat!".to_string()
+                },
+                Message::Room {
+                    time: T0,
+                    name: "lobby".to_string()
+                }
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_line_editing() {
+        let mut app = App::new();
+        for c in "zażółć  gęślą jaźń".chars() {
+            app.add_char(c);
+        }
+        app.move_word_left();
+        assert_eq!(app.cursor_position, 14);
+        app.move_word_left();
+        assert_eq!(app.cursor_position, 8);
+        app.move_word_left();
+        app.move_word_left();
+        assert_eq!(app.cursor_position, 0);
+        app.move_word_right();
+        assert_eq!(app.cursor_position, 6);
+        app.move_word_right();
+        assert_eq!(app.cursor_position, 13);
+
+        app.kill_word();
+        assert_eq!(app.input, "zażółć   jaźń");
+        assert_eq!(app.yank, "gęślą");
+        assert_eq!(app.cursor_position, 8);
+        app.move_cursor_end();
+        app.yank();
+        assert_eq!(app.input, "zażółć   jaźńgęślą");
+        app.move_cursor_start();
+        app.delete_char();
+        assert_eq!(app.input, "ażółć   jaźńgęślą");
+        app.move_word_right();
+        app.kill_to_end();
+        assert_eq!(app.input, "ażółć");
+        assert_eq!(app.yank, "   jaźńgęślą");
+        app.move_cursor_left();
+        app.kill_to_start();
+        assert_eq!(app.input, "ć");
+        assert_eq!(app.yank, "ażół");
+        // nothing to cut keeps what was cut before
+        app.kill_to_start();
+        assert_eq!(app.yank, "ażół");
+        app.move_cursor_end();
+        app.delete_char();
+        assert_eq!(app.input, "ć");
+    }
+
+    #[tokio::test]
+    async fn test_sent_history() {
+        let (mut app, _listener) = connected_app().await;
+        for line in ["first", "second", "second"] {
+            app.input = line.to_string();
+            assert!(app.submit_message());
+            app.clear_input();
+        }
+        assert_eq!(app.history, ["first", "second"]);
+
+        app.history_next();
+        assert_eq!(app.input, "");
+        app.input = "draft".to_string();
+        app.history_previous();
+        assert_eq!(app.input, "second");
+        assert_eq!(app.cursor_position, 6);
+        app.history_previous();
+        app.history_previous();
+        assert_eq!(app.input, "first");
+        app.history_next();
+        assert_eq!(app.input, "second");
+        app.history_next();
+        assert_eq!(app.input, "draft");
+        app.history_next();
+        assert_eq!(app.input, "draft");
+    }
+
+    #[tokio::test]
+    async fn test_tab_completion() {
+        let mut app = App::new();
+        app.roster = vec!["kasia".to_string(), "piotrek".to_string()];
+        app.messages.push(chat("Kamil", "hi"));
+        assert_eq!(app.known_users(), ["Kamil", "kasia", "piotrek"]);
+
+        for c in "/j".chars() {
+            app.add_char(c);
+        }
+        app.complete(true);
+        assert_eq!(app.input, "/join ");
+        assert_eq!(app.cursor_position, 6);
+
+        app.clear_input();
+        app.reset_cursor();
+        app.completion = None;
+        for c in "hi ka".chars() {
+            app.add_char(c);
+        }
+        app.complete(true);
+        assert_eq!(app.input, "hi Kamil ");
+        app.complete(true);
+        assert_eq!(app.input, "hi kasia ");
+        app.complete(true);
+        assert_eq!(app.input, "hi Kamil ");
+        app.complete(false);
+        assert_eq!(app.input, "hi kasia ");
+        assert_eq!(app.cursor_position, 9);
+
+        // only the first word can be a command, and nobody matches
+        app.completion = None;
+        app.add_char('/');
+        app.complete(true);
+        assert_eq!(app.input, "hi kasia /");
+        assert_eq!(app.completion, None);
+    }
+
+    #[tokio::test]
+    async fn test_scrollback() {
+        use ratatui::{backend::TestBackend, Terminal};
+
+        let mut app = App::new();
+        app.screen = Screen::Chat;
+        app.show_roster = false;
+        for i in 0..20 {
+            app.messages.push(chat("kasia", &i.to_string()));
+        }
+        // 10 rows: the help line, 3 for the input and 6 for the chat pane with its borders
+        let mut terminal = Terminal::new(TestBackend::new(60, 10)).unwrap();
+        terminal.draw(|f| crate::ui::render(&mut app, f)).unwrap();
+        assert_eq!(app.page, 4);
+
+        app.scroll_down(app.page);
+        assert_eq!(app.scroll, None);
+        app.scroll_up(app.page);
+        assert_eq!(app.scroll, Some(16));
+        assert_eq!(app.unread(), 4);
+        app.scroll_up(100);
+        assert_eq!(app.scroll, Some(1));
+        terminal.draw(|f| crate::ui::render(&mut app, f)).unwrap();
+        let screen = format!("{:?}", terminal.backend().buffer());
+        assert!(screen.contains("kasia: 0"));
+        assert!(!screen.contains("kasia: 1"));
+        assert!(screen.contains("19 newer messages below"), "{screen}");
+
+        // what arrives while reading back doesn't move the view
+        app.messages.push(chat("piotrek", "hello"));
+        assert_eq!(app.scroll, Some(1));
+        assert_eq!(app.unread(), 20);
+        app.scroll_down(10);
+        assert_eq!(app.scroll, Some(11));
+        app.scroll_down(10);
+        assert_eq!(app.scroll, None);
+        assert_eq!(app.unread(), 0);
+    }
 
-        app.get_messages().unwrap();
-        assert_eq!(app.messages[3], "[00:00] ptr: Hello there!");
+    #[test]
+    fn test_command_palette() {
+        use crate::complete::palette;
+
+        let names = |input| palette(input).iter().map(|c| c.name).collect::<Vec<_>>();
+        assert_eq!(names("/"), chat_proto::COMMANDS.map(|c| c.name));
+        assert_eq!(names("/m"), ["me", "msg"]);
+        assert_eq!(names("/msg"), ["msg"]);
+        assert!(names("/msg kasia").is_empty());
+        assert!(names("//m").is_empty());
+        assert!(names("m").is_empty());
+        assert_eq!(palette("/join")[0].usage(), "/join #room");
+        assert_eq!(palette("/who")[0].usage(), "/who");
+    }
+
+    #[test]
+    fn test_backoff() {
+        use crate::app::backoff;
+        use std::time::Duration;
+
+        assert_eq!(backoff(0), Duration::from_secs(1));
+        assert_eq!(backoff(1), Duration::from_secs(2));
+        assert_eq!(backoff(4), Duration::from_secs(16));
+        assert_eq!(backoff(5), Duration::from_secs(30));
+        assert_eq!(backoff(u32::MAX), Duration::from_secs(30));
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_and_resume() {
+        use std::io::{BufRead, BufReader, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let room = |name: &str| Message::Room {
+            time: Timestamp::UNIX_EPOCH,
+            name: name.to_string(),
+        };
+        let (lobby, away) = (room("lobby"), room("away"));
+        let server = std::thread::spawn(move || {
+            // logs in, then drops the connection; the client comes back and resumes
+            for (expected, reply) in [("register ptr:123456", lobby), ("resume ptr:123456", away)] {
+                let (mut socket, _) = listener.accept().unwrap();
+                let mut line = String::new();
+                BufReader::new(&socket).read_line(&mut line).unwrap();
+                assert_eq!(line.trim_end(), expected);
+                socket
+                    .write_all(&chat_proto::LineCodec::encode(&reply.encode()))
+                    .unwrap();
+            }
+        });
+
+        let args = Server {
+            ip: "127.0.0.1".to_string(),
+            port,
+            tls: false,
+            ca: None,
+            fingerprint: None,
+            server_name: None,
+        };
+        let mut app = App::new();
+        app.connected(Connection::connect(&args).await.unwrap());
+        app.server = Some(args);
+        app.input = "register ptr:123456".to_string();
+        assert!(app.submit_message());
+        let i = wait_for(&mut app, 0, &room("lobby")).await;
+
+        while app.status == Status::Connected {
+            app.get_messages().await.unwrap();
+        }
+        // typing while offline doesn't lose the text
+        app.input = "hello?".to_string();
+        assert!(!app.submit_message());
+        assert_eq!(app.input, "hello?");
+
+        wait_for(&mut app, i + 1, &room("away")).await;
+        assert_eq!(app.status, Status::Connected);
+        assert_eq!(app.room.as_deref(), Some("away"));
+        server.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_survives_cancellation() {
+        use std::time::Duration;
+
+        // takes connections, but never finishes a TLS handshake
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut app = App::new();
+        app.server = Some(Server {
+            tls: true,
+            ..tls_args(listener.local_addr().unwrap().port())
+        });
+        app.status = Status::Reconnecting {
+            attempt: 0,
+            retry_at: tokio::time::Instant::now(),
+        };
+
+        // like the tick or a key press winning the `select!` in `run`
+        for _ in 0..5 {
+            let _ = tokio::time::timeout(Duration::from_millis(50), app.get_messages()).await;
+        }
+        assert!(app.connecting());
+        // the first attempt carried on through all of them instead of starting over
+        let mut attempts = vec![];
+        while let Ok(accepted) =
+            tokio::time::timeout(Duration::from_millis(100), listener.accept()).await
+        {
+            attempts.push(accepted.unwrap());
+        }
+        assert_eq!(attempts.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_fingerprint() {
+        let colons = ["ab"; 32].join(":");
+        assert_eq!(parse_fingerprint(&colons).unwrap(), vec![0xab; 32]);
+        assert_eq!(parse_fingerprint(&"AB".repeat(32)).unwrap(), vec![0xab; 32]);
+        assert!(parse_fingerprint("abcd").is_err());
+        assert!(parse_fingerprint(&"zz".repeat(32)).is_err());
     }
 }
@@ -1,22 +1,37 @@
 use crate::app::App;
+use crate::app::Screen;
 use crate::app::Server;
+use crate::login::LoginForm;
+use crate::time_format::TimeFormat;
 use crate::tui::Tui;
 use crate::update::update;
 use anyhow::Result;
 use clap::Parser;
+use crossterm::event::EventStream;
+use futures::StreamExt;
 use ratatui::{backend::CrosstermBackend, Terminal};
-use std::net::TcpStream;
-
-pub fn run() -> Result<()> {
-    let args = Server::parse();
-    let mut app = App {
-        input: String::new(),
-        cursor_position: 0,
-        messages: vec![],
-        server_socket: TcpStream::connect(format!("{}:{}", args.ip, args.port))?,
-        should_quit: false,
-    };
-    app.server_socket.set_nonblocking(true)?;
+use std::time::Duration;
+
+/// Redraws with nothing else happening, to keep the clock and the reconnect countdown going.
+const TICK: Duration = Duration::from_secs(1);
+
+#[derive(Parser)]
+struct Cli {
+    #[clap(flatten)]
+    server: Server,
+    #[clap(flatten)]
+    time_format: TimeFormat,
+}
+
+pub async fn run() -> Result<()> {
+    let Cli {
+        server: args,
+        time_format,
+    } = Cli::parse();
+    let mut app = App::new();
+    app.screen = Screen::Login(LoginForm::new(format!("{}:{}", args.ip, args.port)));
+    app.server = Some(args);
+    app.time_format = time_format;
 
     let backend = CrosstermBackend::new(std::io::stderr());
     let terminal = Terminal::new(backend)?;
@@ -24,10 +39,20 @@ pub fn run() -> Result<()> {
 
     tui.enter()?;
 
+    let mut events = EventStream::new();
+    let mut tick = tokio::time::interval(TICK);
+    // the screen only changes when one of these does something, so it's drawn only then
     while !app.should_quit {
-        update(&mut app)?;
-
         tui.draw(&mut app)?;
+
+        tokio::select! {
+            event = events.next() => match event {
+                Some(event) => update(&mut app, event?).await,
+                None => break,
+            },
+            result = app.get_messages() => result?,
+            _ = tick.tick() => {}
+        }
     }
 
     tui.exit()?;
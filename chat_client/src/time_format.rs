@@ -0,0 +1,67 @@
+use chat_proto::Timestamp;
+use chrono::format::{Item, StrftimeItems};
+use chrono::{Local, NaiveDate};
+use clap::Args;
+
+pub const DEFAULT_TIME_FORMAT: &str = "%H:%M";
+pub const DEFAULT_DATE_FORMAT: &str = "%A, %-d %B %Y";
+
+/// How the server's timestamps are shown.
+#[derive(Args, Debug, Clone, PartialEq)]
+pub struct TimeFormat {
+    /// strftime format for the time next to every message
+    #[clap(long = "time-format", value_name = "FORMAT", default_value = DEFAULT_TIME_FORMAT, value_parser = parse_format)]
+    pub time: String,
+    /// strftime format for the lines marking a new day
+    #[clap(long = "date-format", value_name = "FORMAT", default_value = DEFAULT_DATE_FORMAT, value_parser = parse_format)]
+    pub date: String,
+    /// Show times in UTC instead of the local time zone
+    #[clap(long)]
+    pub utc: bool,
+}
+
+impl Default for TimeFormat {
+    fn default() -> Self {
+        Self {
+            time: DEFAULT_TIME_FORMAT.to_string(),
+            date: DEFAULT_DATE_FORMAT.to_string(),
+            utc: false,
+        }
+    }
+}
+
+impl TimeFormat {
+    pub fn time(&self, time: &Timestamp) -> String {
+        self.format(time, &self.time)
+    }
+
+    /// The line shown above the first message of a day.
+    pub fn separator(&self, time: &Timestamp) -> String {
+        format!("── {} ──", self.format(time, &self.date))
+    }
+
+    /// The day `time` falls on where the user is.
+    pub fn day(&self, time: &Timestamp) -> NaiveDate {
+        if self.utc {
+            time.date_naive()
+        } else {
+            time.with_timezone(&Local).date_naive()
+        }
+    }
+
+    fn format(&self, time: &Timestamp, format: &str) -> String {
+        if self.utc {
+            time.format(format).to_string()
+        } else {
+            time.with_timezone(&Local).format(format).to_string()
+        }
+    }
+}
+
+/// Rejects formats chrono can't render, which would otherwise panic on the first message.
+fn parse_format(format: &str) -> Result<String, String> {
+    if StrftimeItems::new(format).any(|item| item == Item::Error) {
+        return Err(format!("{format:?} is not a valid strftime format"));
+    }
+    Ok(format.to_string())
+}
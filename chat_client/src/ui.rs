@@ -1,16 +1,42 @@
-use clap::Parser;
 use ratatui::{
     prelude::*,
-    widgets::{block::Position, *},
+    widgets::{
+        block::{Position, Title},
+        *,
+    },
 };
 
+use crate::app::sent_length;
 use crate::app::App;
-use crate::app::Server;
-use crate::helper_fns::{gen_color, split_line};
-use crate::{MAX_LENGTH, SYSTEM_MSG_PREFIX};
+use crate::app::Screen;
+use crate::app::Status;
+use crate::complete::palette;
+use crate::helper_fns::{display_message, gen_color, split_line};
+use crate::login::{Field, LoginForm, Mode};
+use crate::MAX_LENGTH;
+use chat_proto::Message;
+use tokio::time::Instant;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 
-pub fn render(app: &App, f: &mut Frame) {
-    let (user_input, lines_used) = split_line(&app.input, f.size().width as usize - 2, true);
+// fits the longest username plus borders
+const ROSTER_WIDTH: u16 = 16;
+// narrower than this, the chat pane gets the whole width and the roster is hidden
+const MIN_CHAT_WIDTH: u16 = 24;
+const LOGIN_WIDTH: u16 = 50;
+// the fields, the action and up to three lines of what the server said
+const LOGIN_HEIGHT: u16 = 11;
+
+/// Draws `app`, noting how many messages fit for PageUp and PageDown.
+pub fn render(app: &mut App, f: &mut Frame) {
+    if let Screen::Login(form) = &app.screen {
+        return render_login(app, form, f);
+    }
+    let (user_input, lines_used) = split_line(
+        &app.input,
+        (f.size().width as usize).saturating_sub(2),
+        true,
+    );
     let chunks = Layout::default()
         .direction(Direction::Vertical)
         .constraints([
@@ -25,26 +51,59 @@ pub fn render(app: &App, f: &mut Frame) {
         "ESC".bold(),
         " to exit, ".into(),
         "ENTER".bold(),
-        " to send message. ".into(),
+        " to send message, ".into(),
+        "TAB".bold(),
+        " to complete, ".into(),
+        "F2".bold(),
+        " to toggle users. ".into(),
         "Input length: ".into(),
-        format!("{}/{}", app.input.len(), MAX_LENGTH).bold(),
-        ". UTC time: ".into(),
-        format!("{}", chrono::Utc::now().format("%H:%M:%S")).bold(),
+        format!("{}/{}", sent_length(&app.input), MAX_LENGTH).bold(),
+        ". Time: ".into(),
+        app.time_format.time(&chrono::Utc::now()).bold(),
     ];
 
     let help_message = Paragraph::new(Text::from(Line::from(msg)));
     f.render_widget(help_message, chunks[0]);
 
-    let mut msgs = app.messages.clone();
-    let mut sum_lengths = msgs
-        .iter()
-        .map(|m| split_line(m, chunks[1].width as usize - 2, false).1)
-        .sum::<u16>() as usize;
-    while sum_lengths + 2 < chunks[1].height as usize {
-        msgs.insert(0, "".to_string());
+    let (chat_area, roster_area) =
+        if app.show_roster && chunks[1].width >= ROSTER_WIDTH + MIN_CHAT_WIDTH {
+            let columns = Layout::default()
+                .direction(Direction::Horizontal)
+                .constraints([Constraint::Min(1), Constraint::Length(ROSTER_WIDTH)])
+                .split(chunks[1]);
+            (columns[0], Some(columns[1]))
+        } else {
+            (chunks[1], None)
+        };
+
+    if let Some(area) = roster_area {
+        let users: Vec<ListItem> = app
+            .roster
+            .iter()
+            .map(|u| ListItem::new(u.as_str()).style(Style::default().fg(gen_color(u.clone()))))
+            .collect();
+        let roster = List::new(users).block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(format!("Online ({})", app.roster.len())),
+        );
+        f.render_widget(roster, area);
+    }
+
+    let width = (chat_area.width as usize).saturating_sub(2);
+    let end = app.scroll.unwrap_or(app.messages.len());
+    let mut msgs = with_day_separators(app, &app.messages[..end]);
+    let mut shown = msgs.len();
+    // every message takes its rows plus one for a day separator above it
+    let rows = |(separator, m, _): &(Option<String>, String, Style)| {
+        separator.is_some() as usize + split_line(m, width, false).1 as usize
+    };
+    let mut sum_lengths: usize = msgs.iter().map(rows).sum();
+    while sum_lengths + 2 < chat_area.height as usize {
+        msgs.insert(0, (None, String::new(), Style::default()));
         sum_lengths += 1;
     }
-    while sum_lengths + 2 > chunks[1].height as usize {
+    while sum_lengths + 2 > chat_area.height as usize {
         if msgs.is_empty() {
             f.render_widget(
                 Paragraph::new(Text::from(
@@ -54,34 +113,61 @@ pub fn render(app: &App, f: &mut Frame) {
             );
             return;
         }
-        sum_lengths -= split_line(&msgs[0], chunks[1].width as usize - 2, false).1 as usize;
+        sum_lengths -= rows(&msgs[0]);
         msgs.remove(0);
+        shown -= 1;
     }
+    app.page = shown.max(1);
 
     let messages: Vec<ListItem> = msgs
-        .iter()
-        .map(|m| {
-            ListItem::new(Text::from(
-                split_line(m, chunks[1].width as usize - 2, false).0,
-            ))
-            .style(Style::default().fg(if m.starts_with(SYSTEM_MSG_PREFIX) {
-                Color::LightYellow
-            } else if !m.is_empty() {
-                gen_color(m.split(' ').nth(1).unwrap().to_string())
-            } else {
-                Color::default()
-            }))
+        .into_iter()
+        .map(|(separator, m, style)| {
+            let mut text = Text::from(split_line(&m, width, false).0);
+            if let Some(separator) = separator {
+                // not in the message's colors, nor reversed like a private one
+                let style = Style::new().fg(Color::DarkGray).not_italic().not_reversed();
+                let line = Line::styled(separator, style).alignment(Alignment::Center);
+                text.lines.insert(0, line);
+            }
+            ListItem::new(text).style(style)
         })
         .collect();
 
-    let args = Server::parse();
-    let messages = List::new(messages).block(
-        Block::default()
-            .borders(Borders::ALL)
-            .title(format!("Chat @ {}:{}", args.ip, args.port))
-            .title_position(Position::Bottom),
-    );
-    f.render_widget(messages, chunks[1]);
+    let address = app
+        .server
+        .as_ref()
+        .map_or(String::new(), |s| format!("{}:{}", s.ip, s.port));
+    let mut title = match &app.room {
+        Some(room) => format!("#{room} @ {address}"),
+        None => format!("Chat @ {address}"),
+    };
+    if let Status::Reconnecting { attempt, retry_at } = app.status {
+        let attempt = attempt + 1;
+        if app.connecting() {
+            title += &format!(" (offline, reconnect attempt {attempt} under way)");
+        } else {
+            let wait = retry_at.saturating_duration_since(Instant::now()).as_secs();
+            title += &format!(" (offline, reconnect attempt {attempt} in {wait}s)");
+        }
+    }
+    let mut block = Block::default()
+        .borders(Borders::ALL)
+        .title(title)
+        .title_position(Position::Bottom);
+    if app.scroll.is_some() {
+        let below = match app.unread() {
+            1 => " 1 newer message below ".to_string(),
+            n => format!(" {n} newer messages below "),
+        };
+        block = block.title(
+            Title::from(below.bold().reversed())
+                .alignment(Alignment::Right)
+                .position(Position::Bottom),
+        );
+    }
+    let messages = List::new(messages).block(block);
+    f.render_widget(messages, chat_area);
+    render_palette(app, chat_area, f);
 
     let input = Paragraph::new(user_input.clone())
         .block(Block::default().borders(Borders::ALL).title("Input"));
@@ -89,19 +175,166 @@ pub fn render(app: &App, f: &mut Frame) {
 
     let mut cursor_x = chunks[2].x + 1;
     let mut cursor_y = chunks[2].y + 1;
-    let mut chars_seen = 0;
-    for c in user_input.chars() {
-        if c == '\n' {
+    let mut graphemes_seen = 0;
+    // the input itself, to tell the newlines typed from those wrapping added
+    let mut typed = app.input.graphemes(true).peekable();
+    for g in user_input.graphemes(true) {
+        let wrapped = g == "\n" && typed.peek() != Some(&"\n");
+        if !wrapped {
+            if graphemes_seen == app.cursor_position {
+                break;
+            }
+            graphemes_seen += 1;
+            typed.next();
+        }
+        if g == "\n" {
             cursor_x = chunks[2].x + 1;
             cursor_y += 1;
         } else {
-            if chars_seen == app.cursor_position {
-                break;
-            }
-            chars_seen += 1;
-            cursor_x += 1;
+            cursor_x += g.width() as u16;
         }
     }
 
     f.set_cursor(cursor_x, cursor_y);
 }
+
+/// How `messages` are shown, each with a line naming its day above it if that isn't the day
+/// of the message before (or, for the first one, today).
+fn with_day_separators(app: &App, messages: &[Message]) -> Vec<(Option<String>, String, Style)> {
+    let times = &app.time_format;
+    let mut day = times.day(&chrono::Utc::now());
+    messages
+        .iter()
+        .map(|msg| {
+            let (text, style) = display_message(msg, times);
+            let mut separator = None;
+            if let Some(time) = msg.time() {
+                let previous = std::mem::replace(&mut day, times.day(time));
+                if previous != day {
+                    separator = Some(times.separator(time));
+                }
+            }
+            (separator, text, style)
+        })
+        .collect()
+}
+
+/// The commands matching what's typed, over the bottom of the chat pane.
+fn render_palette(app: &App, chat_area: Rect, f: &mut Frame) {
+    let commands = palette(&app.input);
+    if commands.is_empty() {
+        return;
+    }
+    let height = (commands.len() as u16 + 2).min(chat_area.height);
+    let area = Rect {
+        y: chat_area.bottom() - height,
+        height,
+        ..chat_area
+    };
+    let usages: Vec<String> = commands.iter().map(|c| c.usage()).collect();
+    let column = usages.iter().map(|u| u.width()).max().unwrap_or(0) + 2;
+    let selected = app
+        .completion
+        .as_ref()
+        .filter(|c| c.start == 0)
+        .map(|c| c.candidates[c.index].trim_end());
+    let items: Vec<ListItem> = commands
+        .iter()
+        .zip(&usages)
+        .map(|(command, usage)| {
+            let style = if selected == Some(format!("/{}", command.name).as_str()) {
+                Style::default().reversed()
+            } else {
+                Style::default()
+            };
+            ListItem::new(Line::from(vec![
+                format!("{usage:column$}").bold(),
+                command.help.into(),
+            ]))
+            .style(style)
+        })
+        .collect();
+    f.render_widget(Clear, area);
+    let list = List::new(items).block(Block::default().borders(Borders::ALL).title("Commands"));
+    f.render_widget(list, area);
+}
+
+/// The form asking for the credentials, with the server's last word on them below it.
+fn render_login(app: &App, form: &LoginForm, f: &mut Frame) {
+    let width = LOGIN_WIDTH.min(f.size().width);
+    let area = Rect {
+        x: (f.size().width - width) / 2,
+        y: f.size().height.saturating_sub(LOGIN_HEIGHT) / 2,
+        width,
+        height: LOGIN_HEIGHT.min(f.size().height),
+    };
+    let masked = "*".repeat(form.password.chars().count());
+    let fields = [
+        (Field::Username, "Username: ", form.username.as_str()),
+        (Field::Password, "Password: ", masked.as_str()),
+        (Field::Address, "Server:   ", form.address.as_str()),
+    ];
+    let mut lines: Vec<Line> = fields
+        .iter()
+        .map(|(field, label, value)| {
+            let style = if *field == form.focus {
+                Style::default().bold()
+            } else {
+                Style::default()
+            };
+            Line::from(vec![label.bold(), Span::styled(*value, style)])
+        })
+        .collect();
+    let mode = match form.mode {
+        Mode::Login => "Log in",
+        Mode::Register => "Create a new account",
+        Mode::Kick => "Log in, ending the other session",
+    };
+    lines.push(Line::from(vec!["Action:   ".bold(), mode.into()]));
+    lines.push(Line::from(""));
+    // the prompt, or why the last attempt didn't work
+    if let Some((text, style)) = app
+        .messages
+        .iter()
+        .rev()
+        .find(|m| {
+            matches!(
+                m,
+                Message::System { .. } | Message::Error { .. } | Message::Bye { .. }
+            )
+        })
+        .map(|msg| display_message(msg, &app.time_format))
+    {
+        lines.push(Line::from(Span::styled(text, style)));
+    }
+    lines.push(Line::from(vec![
+        "TAB".bold(),
+        " next field, ".into(),
+        "F3".bold(),
+        " action, ".into(),
+        "ENTER".bold(),
+        " go, ".into(),
+        "ESC".bold(),
+        " exit".into(),
+    ]));
+
+    let mut title = "Log in".to_string();
+    if app.connecting_to_log_in() {
+        title += " (connecting…)";
+    } else if let Status::Reconnecting { .. } = app.status {
+        title += " (offline)";
+    }
+    f.render_widget(Clear, area);
+    let login = Paragraph::new(lines)
+        .wrap(Wrap { trim: true })
+        .block(Block::default().borders(Borders::ALL).title(title));
+    f.render_widget(login, area);
+
+    let (row, value) = match form.focus {
+        Field::Username => (0, form.username.as_str()),
+        Field::Password => (1, masked.as_str()),
+        Field::Address => (2, form.address.as_str()),
+    };
+    let x = area.x + 1 + 10 + value.width() as u16;
+    f.set_cursor(x.min(area.right().saturating_sub(2)), area.y + 1 + row);
+}
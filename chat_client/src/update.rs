@@ -1,27 +1,88 @@
-use crate::app::App;
-use anyhow::Result;
-use crossterm::event::{self, Event::Key, KeyCode::Char};
+use crate::app::{App, Screen};
+use crossterm::event::{
+    self, Event, Event::Key, Event::Mouse, KeyCode::Char, KeyEvent, KeyModifiers,
+};
 
-pub fn update(app: &mut App) -> Result<()> {
-    if event::poll(std::time::Duration::from_millis(50))? {
-        if let Key(key) = event::read()? {
-            if key.kind == event::KeyEventKind::Press {
-                match key.code {
-                    Char(c) => app.add_char(c),
-                    event::KeyCode::Backspace => app.remove_char(),
-                    event::KeyCode::Enter => {
-                        app.submit_message()?;
-                        app.reset_cursor();
-                        app.clear_input();
-                    }
-                    event::KeyCode::Left => app.move_cursor_left(),
-                    event::KeyCode::Right => app.move_cursor_right(),
-                    event::KeyCode::Esc => app.should_quit = true,
-                    _ => {}
-                }
-            }
+/// Messages one turn of the mouse wheel scrolls by.
+const WHEEL_STEP: usize = 3;
+
+/// Applies one terminal event; anything but a key press or the mouse wheel (e.g. a
+/// resize) only needs a redraw.
+pub async fn update(app: &mut App, event: Event) {
+    match event {
+        Key(key) if key.kind == event::KeyEventKind::Press => match app.screen {
+            Screen::Login(_) => login_key(app, key),
+            Screen::Chat => chat_key(app, key),
+        },
+        Mouse(mouse) if app.screen == Screen::Chat => match mouse.kind {
+            event::MouseEventKind::ScrollUp => app.scroll_up(WHEEL_STEP),
+            event::MouseEventKind::ScrollDown => app.scroll_down(WHEEL_STEP),
+            _ => {}
+        },
+        _ => {}
+    }
+}
+
+fn login_key(app: &mut App, key: KeyEvent) {
+    let Screen::Login(form) = &mut app.screen else {
+        return;
+    };
+    match key.code {
+        Char(c) => form.add_char(c),
+        event::KeyCode::Backspace => form.remove_char(),
+        event::KeyCode::Tab | event::KeyCode::Down => form.next_field(),
+        event::KeyCode::BackTab | event::KeyCode::Up => form.previous_field(),
+        event::KeyCode::F(3) => form.next_mode(),
+        event::KeyCode::Enter => app.submit_login(),
+        event::KeyCode::Esc => app.should_quit = true,
+        _ => {}
+    }
+}
+
+fn chat_key(app: &mut App, key: KeyEvent) {
+    if !matches!(key.code, event::KeyCode::Tab | event::KeyCode::BackTab) {
+        // anything else settles on the candidate shown
+        app.completion = None;
+    }
+    let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+    let alt = key.modifiers.contains(KeyModifiers::ALT);
+    match key.code {
+        // emacs, like readline
+        Char('a') if ctrl => app.move_cursor_start(),
+        Char('e') if ctrl => app.move_cursor_end(),
+        Char('b') if alt => app.move_word_left(),
+        Char('f') if alt => app.move_word_right(),
+        Char('w') if ctrl => app.kill_word(),
+        Char('u') if ctrl => app.kill_to_start(),
+        Char('k') if ctrl => app.kill_to_end(),
+        Char('y') if ctrl => app.yank(),
+        // Windows reports AltGr (e.g. ą, ł or @ on a Polish layout) as CONTROL|ALT
+        Char(c) if ctrl == alt => app.add_char(c),
+        event::KeyCode::Backspace => app.remove_char(),
+        event::KeyCode::Delete => app.delete_char(),
+        // plain ENTER sends, so a line break needs a modifier (not every terminal reports SHIFT)
+        event::KeyCode::Enter if alt || key.modifiers.contains(KeyModifiers::SHIFT) => {
+            app.add_char('\n')
+        }
+        // the input stays put if it couldn't be sent
+        event::KeyCode::Enter if app.submit_message() => {
+            app.reset_cursor();
+            app.clear_input();
         }
+        event::KeyCode::Left if ctrl => app.move_word_left(),
+        event::KeyCode::Right if ctrl => app.move_word_right(),
+        event::KeyCode::Left => app.move_cursor_left(),
+        event::KeyCode::Right => app.move_cursor_right(),
+        event::KeyCode::Home => app.move_cursor_start(),
+        event::KeyCode::End => app.move_cursor_end(),
+        event::KeyCode::Up => app.history_previous(),
+        event::KeyCode::Down => app.history_next(),
+        event::KeyCode::Tab => app.complete(true),
+        event::KeyCode::BackTab => app.complete(false),
+        event::KeyCode::PageUp => app.scroll_up(app.page),
+        event::KeyCode::PageDown => app.scroll_down(app.page),
+        event::KeyCode::F(2) => app.show_roster = !app.show_roster,
+        event::KeyCode::Esc => app.should_quit = true,
+        _ => {}
     }
-    app.get_messages()?;
-    Ok(())
 }
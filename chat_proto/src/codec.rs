@@ -0,0 +1,111 @@
+use std::fmt;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum FrameError {
+    TooLong,
+    InvalidUtf8,
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::TooLong => write!(f, "frame too long"),
+            FrameError::InvalidUtf8 => write!(f, "frame is not valid UTF-8"),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// The longest frame clients read. Single frames can carry a whole history replay, so
+/// this is much longer than anything a user can type; the server keeps replays under it.
+pub const MAX_FRAME_LENGTH: usize = 256_000;
+
+/// Splits a byte stream into newline-terminated frames.
+///
+/// Reads can be fed in arbitrary pieces: partial frames are buffered until their
+/// newline arrives, and one read may yield several frames. A frame longer than
+/// `max_len` is reported once and then skipped up to its newline.
+pub struct LineCodec {
+    buf: Vec<u8>,
+    scanned: usize,
+    max_len: usize,
+    discarding: bool,
+}
+
+impl LineCodec {
+    pub fn new(max_len: usize) -> Self {
+        Self {
+            buf: Vec::new(),
+            scanned: 0,
+            max_len,
+            discarding: false,
+        }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Returns the next complete frame without its line terminator, or `None`
+    /// if more data is needed.
+    pub fn next_frame(&mut self) -> Option<Result<String, FrameError>> {
+        loop {
+            let Some(pos) = self.buf[self.scanned..].iter().position(|&b| b == b'\n') else {
+                self.scanned = self.buf.len();
+                if self.buf.len() > self.max_len {
+                    self.buf.clear();
+                    self.scanned = 0;
+                    if !self.discarding {
+                        self.discarding = true;
+                        return Some(Err(FrameError::TooLong));
+                    }
+                }
+                return None;
+            };
+
+            let end = self.scanned + pos;
+            let mut frame: Vec<u8> = self.buf.drain(..=end).collect();
+            self.scanned = 0;
+            frame.pop();
+            if frame.last() == Some(&b'\r') {
+                frame.pop();
+            }
+
+            if self.discarding {
+                self.discarding = false;
+                continue;
+            }
+            if frame.len() > self.max_len {
+                return Some(Err(FrameError::TooLong));
+            }
+            return Some(String::from_utf8(frame).map_err(|_| FrameError::InvalidUtf8));
+        }
+    }
+
+    /// Terminates `frame` for the wire.
+    pub fn encode(frame: &str) -> Vec<u8> {
+        let mut out = Vec::with_capacity(frame.len() + 1);
+        out.extend_from_slice(frame.as_bytes());
+        out.push(b'\n');
+        out
+    }
+}
+
+/// Stands for a line break inside a frame, which a newline would end.
+///
+/// Clients send a multi-line message as one frame with its lines joined by this
+/// (U+2028 LINE SEPARATOR); the server turns it back into `\n`, so the messages it sends
+/// carry real newlines.
+pub const LINE_SEPARATOR: char = '\u{2028}';
+
+/// `text` with its newlines swapped for [`LINE_SEPARATOR`], ready to go out as one frame.
+pub fn join_lines(text: &str) -> String {
+    text.replace("\r\n", "\n")
+        .replace('\n', &LINE_SEPARATOR.to_string())
+}
+
+/// Undoes [`join_lines`].
+pub fn split_lines(frame: &str) -> String {
+    frame.replace(LINE_SEPARATOR, "\n")
+}
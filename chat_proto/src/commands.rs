@@ -0,0 +1,54 @@
+/// A command the server understands, as `/help` describes it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandInfo {
+    /// Without the `/`.
+    pub name: &'static str,
+    /// The arguments as shown by `/help` and in usage errors, e.g. `#room`.
+    pub args: &'static str,
+    /// One line for `/help`.
+    pub help: &'static str,
+}
+
+impl CommandInfo {
+    /// How to call it, e.g. `/join #room`.
+    pub fn usage(&self) -> String {
+        format!("/{} {}", self.name, self.args)
+            .trim_end()
+            .to_string()
+    }
+}
+
+const fn command(name: &'static str, args: &'static str, help: &'static str) -> CommandInfo {
+    CommandInfo { name, args, help }
+}
+
+pub const ANNOUNCE: CommandInfo = command(
+    "announce",
+    "[message]",
+    "Tells everyone online, whatever room they're in",
+);
+pub const HELP: CommandInfo = command("help", "[command]", "Lists the commands, or explains one");
+pub const JOIN: CommandInfo = command(
+    "join",
+    "#room",
+    "Moves you to a room, creating it if needed",
+);
+pub const LIST: CommandInfo = command("list", "", "Lists the rooms and how many are in each");
+pub const ME: CommandInfo = command(
+    "me",
+    "[action]",
+    "Tells the room what you're doing, e.g. /me waves",
+);
+pub const MSG: CommandInfo = command("msg", "[user] [message]", "Sends a private message");
+pub const NICK: CommandInfo = command(
+    "nick",
+    "[nickname]",
+    "Changes the name you chat under, or goes back to your username",
+);
+pub const PART: CommandInfo = command("part", "", "Goes back to #lobby");
+pub const QUIT: CommandInfo = command("quit", "", "Logs you out");
+pub const WHO: CommandInfo = command("who", "", "Lists everyone online");
+
+/// The server's built-in commands, sorted by name.
+pub const COMMANDS: [CommandInfo; 10] =
+    [ANNOUNCE, HELP, JOIN, LIST, ME, MSG, NICK, PART, QUIT, WHO];
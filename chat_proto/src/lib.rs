@@ -0,0 +1,197 @@
+pub mod codec;
+pub mod commands;
+pub mod message;
+
+pub use codec::{join_lines, split_lines, FrameError, LineCodec, LINE_SEPARATOR, MAX_FRAME_LENGTH};
+pub use commands::{CommandInfo, COMMANDS};
+pub use message::{DecodeError, Message, Timestamp, PROTOCOL_VERSION};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames(codec: &mut LineCodec) -> Vec<Result<String, FrameError>> {
+        std::iter::from_fn(|| codec.next_frame()).collect()
+    }
+
+    #[test]
+    fn batched_frames() {
+        let mut codec = LineCodec::new(64);
+        codec.feed(b"first\nsecond\r\nthird\n");
+        assert_eq!(
+            frames(&mut codec),
+            vec![
+                Ok("first".to_string()),
+                Ok("second".to_string()),
+                Ok("third".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn fragmented_frames() {
+        let mut codec = LineCodec::new(64);
+        for b in b"Hello there!\nGeneral" {
+            codec.feed(&[*b]);
+        }
+        assert_eq!(frames(&mut codec), vec![Ok("Hello there!".to_string())]);
+        codec.feed(b" Kenobi!");
+        assert_eq!(codec.next_frame(), None);
+        codec.feed(b"\n");
+        assert_eq!(frames(&mut codec), vec![Ok("General Kenobi!".to_string())]);
+    }
+
+    #[test]
+    fn split_code_point() {
+        let mut codec = LineCodec::new(64);
+        let data = "zażółć\n".as_bytes();
+        codec.feed(&data[..3]);
+        assert_eq!(codec.next_frame(), None);
+        codec.feed(&data[3..]);
+        assert_eq!(codec.next_frame(), Some(Ok("zażółć".to_string())));
+        codec.feed(b"\xff\xfe\n");
+        assert_eq!(codec.next_frame(), Some(Err(FrameError::InvalidUtf8)));
+    }
+
+    #[test]
+    fn too_long_frames_are_skipped() {
+        let mut codec = LineCodec::new(4);
+        codec.feed(b"12345");
+        assert_eq!(codec.next_frame(), Some(Err(FrameError::TooLong)));
+        codec.feed(b"6789");
+        assert_eq!(codec.next_frame(), None);
+        codec.feed(b"0\nok\n123456\n");
+        assert_eq!(
+            frames(&mut codec),
+            vec![Ok("ok".to_string()), Err(FrameError::TooLong)]
+        );
+    }
+
+    #[test]
+    fn encode_terminates_frame() {
+        let mut codec = LineCodec::new(64);
+        codec.feed(&LineCodec::encode("abc"));
+        codec.feed(&LineCodec::encode(""));
+        assert_eq!(
+            frames(&mut codec),
+            vec![Ok("abc".to_string()), Ok(String::new())]
+        );
+    }
+
+    #[test]
+    fn multi_line_frames() {
+        let text = "first\nsecond\r\n\nlast";
+        let frame = join_lines(text);
+        assert_eq!(frame, "first\u{2028}second\u{2028}\u{2028}last");
+        let mut codec = LineCodec::new(64);
+        codec.feed(&LineCodec::encode(&frame));
+        assert_eq!(frames(&mut codec), vec![Ok(frame.clone())]);
+        assert_eq!(split_lines(&frame), "first\nsecond\n\nlast");
+        assert_eq!(split_lines("no breaks"), "no breaks");
+    }
+
+    fn at(time: &str) -> Timestamp {
+        format!("2024-03-10T{time}:00Z").parse().unwrap()
+    }
+
+    #[test]
+    fn message_round_trip() {
+        let messages = [
+            Message::System {
+                time: at("12:34"),
+                text: "Welcome to the chat!".to_string(),
+            },
+            Message::Chat {
+                time: at("12:35"),
+                from: "piotrek".to_string(),
+                text: "a line with \"quotes\", spaces: and \n newlines".to_string(),
+            },
+            Message::Action {
+                time: at("12:35"),
+                from: "kasia".to_string(),
+                text: "waves".to_string(),
+            },
+            Message::Direct {
+                time: at("12:35"),
+                from: "piotrek".to_string(),
+                to: "kasia".to_string(),
+                text: "psst".to_string(),
+            },
+            Message::Join {
+                time: at("12:36"),
+                user: "kasia".to_string(),
+                room: "lobby".to_string(),
+            },
+            Message::Leave {
+                time: at("12:37"),
+                user: "kasia".to_string(),
+                room: "lobby".to_string(),
+            },
+            Message::Room {
+                time: at("12:37"),
+                name: "rust".to_string(),
+            },
+            Message::Roster {
+                users: vec!["kasia".to_string(), "piotrek".to_string()],
+            },
+            Message::History {
+                room: "lobby".to_string(),
+                messages: vec![Message::Chat {
+                    time: at("12:00"),
+                    from: "piotrek".to_string(),
+                    text: "earlier".to_string(),
+                }],
+            },
+            Message::Error {
+                time: at("12:38"),
+                text: "Wrong username or password".to_string(),
+            },
+            Message::Bye {
+                time: at("12:39"),
+                text: "Bye!".to_string(),
+            },
+        ];
+        for msg in messages {
+            let line = msg.encode();
+            assert!(!line.contains('\n'));
+            assert_eq!(Message::decode(&line), Ok(msg));
+        }
+    }
+
+    #[test]
+    fn message_wire_format() {
+        let msg = Message::Chat {
+            time: at("00:00"),
+            from: "ptr".to_string(),
+            text: "hi".to_string(),
+        };
+        assert_eq!(
+            msg.encode(),
+            r#"{"v":2,"type":"chat","time":"2024-03-10T00:00:00Z","from":"ptr","text":"hi"}"#
+        );
+    }
+
+    #[test]
+    fn message_decode_errors() {
+        assert_eq!(
+            Message::decode(r#"{"v":1,"type":"system","time":"00:00","text":"hi"}"#),
+            Err(DecodeError::UnsupportedVersion(1))
+        );
+        assert!(matches!(
+            Message::decode(r#"{"type":"system","time":"00:00","text":"hi"}"#),
+            Err(DecodeError::Malformed(_))
+        ));
+        assert!(matches!(
+            Message::decode(r#"{"v":2,"type":"shout","text":"hi"}"#),
+            Err(DecodeError::Malformed(_))
+        ));
+        assert!(matches!(
+            Message::decode(r#"{"v":2,"type":"system","time":"00:00","text":"hi"}"#),
+            Err(DecodeError::Malformed(_))
+        ));
+        assert!(matches!(
+            Message::decode("[00:00] ptr: hi"),
+            Err(DecodeError::Malformed(_))
+        ));
+    }
+}
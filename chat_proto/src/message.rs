@@ -0,0 +1,129 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Bumped whenever a change to [`Message`] would confuse older peers.
+///
+/// 2: `time` became a full RFC 3339 timestamp instead of `HH:MM` in UTC.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// When something happened, in UTC; clients pick the time zone and format to show.
+pub type Timestamp = chrono::DateTime<chrono::Utc>;
+
+/// Everything the server sends to a client.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    /// Informational notice from the server itself.
+    System { time: Timestamp, text: String },
+    /// A line written by `from`.
+    Chat {
+        time: Timestamp,
+        from: String,
+        text: String,
+    },
+    /// Something `from` did, written in the third person, e.g. `/me waves`.
+    Action {
+        time: Timestamp,
+        from: String,
+        text: String,
+    },
+    /// A private message, only `from` and `to` get it.
+    Direct {
+        time: Timestamp,
+        from: String,
+        to: String,
+        text: String,
+    },
+    Join {
+        time: Timestamp,
+        user: String,
+        room: String,
+    },
+    Leave {
+        time: Timestamp,
+        user: String,
+        room: String,
+    },
+    /// The recipient is now talking in `name`.
+    Room { time: Timestamp, name: String },
+    /// The latest messages of `room`, oldest first, replayed after logging in.
+    History {
+        room: String,
+        messages: Vec<Message>,
+    },
+    /// Everyone currently online by the name they go by, sent whenever somebody logs in or
+    /// out or changes nickname.
+    Roster { users: Vec<String> },
+    /// Something the recipient did was rejected.
+    Error { time: Timestamp, text: String },
+    /// The server is ending the session on purpose, so the client shouldn't reconnect.
+    Bye { time: Timestamp, text: String },
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    UnsupportedVersion(u32),
+    Malformed(String),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnsupportedVersion(v) => write!(
+                f,
+                "protocol version {v} is not supported (expected {PROTOCOL_VERSION})"
+            ),
+            DecodeError::Malformed(e) => write!(f, "malformed message: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+#[derive(Serialize)]
+struct Outgoing<'a> {
+    v: u32,
+    #[serde(flatten)]
+    msg: &'a Message,
+}
+
+#[derive(Deserialize)]
+struct Version {
+    v: u32,
+}
+
+impl Message {
+    /// When it happened, `None` for the bundles that carry messages of their own or none.
+    pub fn time(&self) -> Option<&Timestamp> {
+        match self {
+            Message::System { time, .. }
+            | Message::Chat { time, .. }
+            | Message::Action { time, .. }
+            | Message::Direct { time, .. }
+            | Message::Join { time, .. }
+            | Message::Leave { time, .. }
+            | Message::Room { time, .. }
+            | Message::Error { time, .. }
+            | Message::Bye { time, .. } => Some(time),
+            Message::History { .. } | Message::Roster { .. } => None,
+        }
+    }
+
+    /// Serializes the message into a single line (without the terminator).
+    pub fn encode(&self) -> String {
+        serde_json::to_string(&Outgoing {
+            v: PROTOCOL_VERSION,
+            msg: self,
+        })
+        .expect("messages always serialize")
+    }
+
+    pub fn decode(frame: &str) -> Result<Message, DecodeError> {
+        let Version { v } =
+            serde_json::from_str(frame).map_err(|e| DecodeError::Malformed(e.to_string()))?;
+        if v != PROTOCOL_VERSION {
+            return Err(DecodeError::UnsupportedVersion(v));
+        }
+        serde_json::from_str(frame).map_err(|e| DecodeError::Malformed(e.to_string()))
+    }
+}
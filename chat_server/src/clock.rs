@@ -0,0 +1,26 @@
+use chat_proto::Timestamp;
+
+/// Where the timestamps on messages come from.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Timestamp;
+}
+
+/// The real time, what the server runs on.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Timestamp {
+        chrono::Utc::now()
+    }
+}
+
+/// Stuck at one moment, so tests know every timestamp in advance.
+#[cfg(any(test, feature = "testing"))]
+pub struct FixedClock(pub Timestamp);
+
+#[cfg(any(test, feature = "testing"))]
+impl Clock for FixedClock {
+    fn now(&self) -> Timestamp {
+        self.0
+    }
+}
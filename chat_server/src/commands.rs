@@ -0,0 +1,353 @@
+use crate::handler::{
+    action_msg, broadcast, bye_msg, direct_msg, error_msg, room_msg, system_msg, CurrentRoom,
+};
+use crate::rooms::{parse_room_name, DEFAULT_ROOM};
+use crate::state::State;
+use crate::users::validate_username;
+use chat_proto::{commands, CommandInfo, Message};
+use std::collections::BTreeMap;
+
+/// Who may run a command; admins can run everything users can.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Permission {
+    User,
+    Admin,
+}
+
+/// Why a command didn't run, sent back to the user as an error.
+#[derive(Debug, PartialEq)]
+pub enum CommandError {
+    /// The arguments don't make sense, answered with the command's usage.
+    Usage,
+    Failed(String),
+}
+
+/// What a command gets to see and change: who runs it and where they are.
+pub struct Context<'a> {
+    pub state: &'a State,
+    pub uname: &'a str,
+    /// What the user goes by in the chat, their username unless they picked a nickname.
+    pub nick: &'a mut String,
+    pub room: &'a mut CurrentRoom,
+    /// Sent to the user, in order, once the command is done.
+    pub replies: Vec<Message>,
+    /// Set to hang up once the replies are out.
+    pub quit: bool,
+}
+
+impl Context<'_> {
+    pub fn reply(&mut self, msg: Message) {
+        self.replies.push(msg);
+    }
+
+    pub fn permission(&self) -> Permission {
+        if self.state.config.admins.iter().any(|a| a == self.uname) {
+            Permission::Admin
+        } else {
+            Permission::User
+        }
+    }
+}
+
+/// A `/name args` line a logged in user can send instead of a chat message.
+pub trait Command: Send + Sync {
+    /// Its name, arguments and help, which clients know from `chat_proto` too.
+    fn info(&self) -> &'static CommandInfo;
+    fn permission(&self) -> Permission {
+        Permission::User
+    }
+    /// Runs with `args` already trimmed.
+    fn run(&self, ctx: &mut Context, args: &str) -> Result<(), CommandError>;
+}
+
+/// Every command the server knows, by name.
+#[derive(Default)]
+pub struct Commands {
+    commands: BTreeMap<&'static str, Box<dyn Command>>,
+}
+
+impl Commands {
+    /// The commands every server has.
+    pub fn builtin() -> Self {
+        let mut commands = Self::default();
+        commands.register(Help);
+        commands.register(Join);
+        commands.register(Part);
+        commands.register(List);
+        commands.register(Who);
+        commands.register(Msg);
+        commands.register(Me);
+        commands.register(Nick);
+        commands.register(Quit);
+        commands.register(Announce);
+        commands
+    }
+
+    /// Adds `command`, replacing any other with the same name.
+    pub fn register(&mut self, command: impl Command + 'static) {
+        self.commands.insert(command.info().name, Box::new(command));
+    }
+
+    /// Those `permission` allows, sorted by name.
+    pub fn available(&self, permission: Permission) -> impl Iterator<Item = &dyn Command> {
+        self.commands
+            .values()
+            .map(|c| c.as_ref())
+            .filter(move |c| c.permission() <= permission)
+    }
+
+    /// Runs `line` if it's a command, `false` means it's a chat message.
+    ///
+    /// `//` escapes a chat message that starts with a slash.
+    pub fn run(&self, ctx: &mut Context, line: &str) -> bool {
+        let Some(line) = line.strip_prefix('/').filter(|l| !l.starts_with('/')) else {
+            return false;
+        };
+        let (name, args) = line.split_once(' ').unwrap_or((line, ""));
+        let result = match self.commands.get(name) {
+            None => Err(CommandError::Failed(format!(
+                "Unknown command /{name}, see /help"
+            ))),
+            Some(c) if c.permission() > ctx.permission() => {
+                Err(CommandError::Failed(format!("Only admins can use /{name}")))
+            }
+            Some(c) => c.run(ctx, args.trim()).map_err(|e| match e {
+                CommandError::Usage => CommandError::Failed(format!("Usage: {}", c.info().usage())),
+                e => e,
+            }),
+        };
+        if let Err(CommandError::Failed(e)) = result {
+            ctx.reply(error_msg(ctx.state.now(), &e));
+        }
+        true
+    }
+}
+
+struct Help;
+
+impl Command for Help {
+    fn info(&self) -> &'static CommandInfo {
+        &commands::HELP
+    }
+    fn run(&self, ctx: &mut Context, args: &str) -> Result<(), CommandError> {
+        let wanted = args.strip_prefix('/').unwrap_or(args);
+        let lines: Vec<String> = ctx
+            .state
+            .commands
+            .available(ctx.permission())
+            .map(|c| c.info())
+            .filter(|c| wanted.is_empty() || c.name == wanted)
+            .map(|c| format!("{} - {}", c.usage(), c.help))
+            .collect();
+        if lines.is_empty() {
+            return Err(CommandError::Failed(format!("Unknown command /{wanted}")));
+        }
+        if wanted.is_empty() {
+            ctx.reply(system_msg(
+                ctx.state.now(),
+                "Commands (start a message with // to send a /):",
+            ));
+        }
+        for line in lines {
+            ctx.reply(system_msg(ctx.state.now(), &line));
+        }
+        Ok(())
+    }
+}
+
+struct Join;
+
+impl Command for Join {
+    fn info(&self) -> &'static CommandInfo {
+        &commands::JOIN
+    }
+    fn run(&self, ctx: &mut Context, args: &str) -> Result<(), CommandError> {
+        let target = parse_room_name(args).ok_or(CommandError::Usage)?;
+        if target == ctx.room.name {
+            return Err(CommandError::Failed(format!(
+                "You are already in #{target}"
+            )));
+        }
+        ctx.reply(room_msg(ctx.state.now(), target));
+        ctx.room.switch(ctx.state, ctx.uname, target);
+        Ok(())
+    }
+}
+
+struct Part;
+
+impl Command for Part {
+    fn info(&self) -> &'static CommandInfo {
+        &commands::PART
+    }
+    fn run(&self, ctx: &mut Context, _args: &str) -> Result<(), CommandError> {
+        if ctx.room.name == DEFAULT_ROOM {
+            return Err(CommandError::Failed(format!(
+                "You can't leave #{DEFAULT_ROOM}"
+            )));
+        }
+        ctx.reply(room_msg(ctx.state.now(), DEFAULT_ROOM));
+        ctx.room.switch(ctx.state, ctx.uname, DEFAULT_ROOM);
+        Ok(())
+    }
+}
+
+struct List;
+
+impl Command for List {
+    fn info(&self) -> &'static CommandInfo {
+        &commands::LIST
+    }
+    fn run(&self, ctx: &mut Context, _args: &str) -> Result<(), CommandError> {
+        let list: Vec<String> = ctx
+            .state
+            .rooms
+            .list()
+            .iter()
+            .map(|(name, members)| format!("#{name} ({members})"))
+            .collect();
+        ctx.reply(system_msg(
+            ctx.state.now(),
+            &format!("Rooms: {}", list.join(", ")),
+        ));
+        Ok(())
+    }
+}
+
+struct Who;
+
+impl Command for Who {
+    fn info(&self) -> &'static CommandInfo {
+        &commands::WHO
+    }
+    fn run(&self, ctx: &mut Context, _args: &str) -> Result<(), CommandError> {
+        let online: Vec<String> = ctx
+            .state
+            .sessions
+            .who()
+            .into_iter()
+            .map(|(uname, nick)| match nick {
+                Some(nick) => format!("{nick} ({uname})"),
+                None => uname,
+            })
+            .collect();
+        let msg = format!("Online ({}): {}", online.len(), online.join(", "));
+        ctx.reply(system_msg(ctx.state.now(), &msg));
+        Ok(())
+    }
+}
+
+struct Msg;
+
+impl Command for Msg {
+    fn info(&self) -> &'static CommandInfo {
+        &commands::MSG
+    }
+    fn run(&self, ctx: &mut Context, args: &str) -> Result<(), CommandError> {
+        let (to, text) = args.split_once(' ').ok_or(CommandError::Usage)?;
+        let text = text.trim();
+        if to.is_empty() || text.is_empty() {
+            return Err(CommandError::Usage);
+        }
+        let not_online = || CommandError::Failed(format!("{to} is not online"));
+        let (uname, shown) = ctx.state.sessions.find(to).ok_or_else(not_online)?;
+        let msg = direct_msg(ctx.state.now(), ctx.nick, &shown, text);
+        if uname != ctx.uname && !ctx.state.sessions.send_to(&uname, msg.clone()) {
+            return Err(not_online());
+        }
+        // echoed, so the sender sees it too
+        ctx.reply(msg);
+        Ok(())
+    }
+}
+
+struct Me;
+
+impl Command for Me {
+    fn info(&self) -> &'static CommandInfo {
+        &commands::ME
+    }
+    fn run(&self, ctx: &mut Context, args: &str) -> Result<(), CommandError> {
+        if args.is_empty() {
+            return Err(CommandError::Usage);
+        }
+        let msg = action_msg(ctx.state.now(), ctx.nick, args);
+        broadcast(ctx.state, &ctx.room.tx, &ctx.room.name, msg);
+        Ok(())
+    }
+}
+
+struct Nick;
+
+impl Command for Nick {
+    fn info(&self) -> &'static CommandInfo {
+        &commands::NICK
+    }
+    fn run(&self, ctx: &mut Context, args: &str) -> Result<(), CommandError> {
+        let new = if args.is_empty() { ctx.uname } else { args };
+        if new == ctx.nick.as_str() {
+            return Err(CommandError::Failed(format!("You already go by {new}")));
+        }
+        if new != ctx.uname {
+            validate_username(new)
+                .map_err(|e| CommandError::Failed(format!("Invalid nickname: {e}")))?;
+            // nobody gets to pass for somebody else
+            if ctx.state.users.exists(new) {
+                return Err(CommandError::Failed(format!(
+                    "{new} is somebody's username"
+                )));
+            }
+        }
+        let nick = (new != ctx.uname).then_some(new);
+        if !ctx.state.sessions.set_nick(ctx.uname, nick) {
+            return Err(CommandError::Failed(format!(
+                "Somebody online goes by {new}"
+            )));
+        }
+        ctx.state.sessions.broadcast_roster();
+        let text = format!("{} is now known as {new}", ctx.nick);
+        broadcast(
+            ctx.state,
+            &ctx.room.tx,
+            &ctx.room.name,
+            system_msg(ctx.state.now(), &text),
+        );
+        *ctx.nick = new.to_string();
+        Ok(())
+    }
+}
+
+struct Quit;
+
+impl Command for Quit {
+    fn info(&self) -> &'static CommandInfo {
+        &commands::QUIT
+    }
+    fn run(&self, ctx: &mut Context, _args: &str) -> Result<(), CommandError> {
+        // a bye, not a notice, so the client doesn't take the hang-up for a dropped link
+        ctx.reply(bye_msg(ctx.state.now(), "Bye!"));
+        ctx.quit = true;
+        Ok(())
+    }
+}
+
+struct Announce;
+
+impl Command for Announce {
+    fn info(&self) -> &'static CommandInfo {
+        &commands::ANNOUNCE
+    }
+    fn permission(&self) -> Permission {
+        Permission::Admin
+    }
+    fn run(&self, ctx: &mut Context, args: &str) -> Result<(), CommandError> {
+        if args.is_empty() {
+            return Err(CommandError::Usage);
+        }
+        let text = format!("Announcement from {}: {args}", ctx.uname);
+        ctx.state
+            .sessions
+            .broadcast(&system_msg(ctx.state.now(), &text));
+        Ok(())
+    }
+}
@@ -0,0 +1,238 @@
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
+use log::LevelFilter;
+use serde::Deserialize;
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+};
+
+pub const DEFAULT_PORT: u16 = 8080;
+/// Lines longer than this are rejected, usernames and passwords included.
+pub const DEFAULT_MAX_LINE_LENGTH: usize = 1024;
+/// Below this even the login line might not fit.
+pub const MIN_MAX_LINE_LENGTH: usize = 64;
+
+#[derive(Parser, Debug)]
+#[command(about = "Chat server")]
+pub struct Cli {
+    /// TOML file with the settings, the flags below override it
+    #[arg(short, long, env = "CHAT_CONFIG", value_name = "FILE")]
+    pub config: Option<PathBuf>,
+    /// Address to listen on, repeat it to listen on several
+    #[arg(long, value_name = "ADDR")]
+    pub bind: Vec<IpAddr>,
+    #[arg(short, long)]
+    pub port: Option<u16>,
+    #[arg(long, env = "CHAT_USERS_DB", value_name = "FILE")]
+    pub users_db: Option<PathBuf>,
+    #[arg(long, env = "CHAT_HISTORY_LOG", value_name = "FILE")]
+    pub history_log: Option<PathBuf>,
+    /// Message of the day, shown to everyone after logging in
+    #[arg(long)]
+    pub motd: Option<String>,
+    /// User allowed to run admin commands, repeat it for several
+    #[arg(long = "admin", value_name = "USER")]
+    pub admins: Vec<String>,
+    #[arg(long, value_name = "BYTES")]
+    pub max_line_length: Option<usize>,
+    /// Messages a room buffers per member before slow ones start missing them
+    #[arg(long, env = "CHAT_ROOM_CAPACITY", value_name = "MESSAGES")]
+    pub room_capacity: Option<usize>,
+    /// Messages replayed from the history after logging in
+    #[arg(long, value_name = "MESSAGES")]
+    pub replay_length: Option<usize>,
+    /// Missed messages resent after falling behind or resuming, 0 turns that off
+    #[arg(long, value_name = "MESSAGES")]
+    pub backfill: Option<usize>,
+    /// PEM certificate chain, turns on TLS together with `--tls-key`
+    #[arg(long, env = "CHAT_TLS_CERT", value_name = "FILE")]
+    pub tls_cert: Option<PathBuf>,
+    #[arg(long, env = "CHAT_TLS_KEY", value_name = "FILE")]
+    pub tls_key: Option<PathBuf>,
+    /// One of off, error, warn, info, debug or trace
+    #[arg(long, value_name = "LEVEL")]
+    pub log_level: Option<LevelFilter>,
+    /// Append the log to this file instead of printing it
+    #[arg(long, value_name = "FILE")]
+    pub log_file: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Creates an account and exits
+    Adduser { username: String, password: String },
+}
+
+/// Server settings, read from the config file with the command line applied on top.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind: Vec<IpAddr>,
+    pub port: u16,
+    pub users_db: PathBuf,
+    pub history_log: PathBuf,
+    pub motd: Option<String>,
+    /// Users allowed to run admin commands.
+    pub admins: Vec<String>,
+    pub limits: Limits,
+    pub tls: Option<Tls>,
+    pub log: Log,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    pub max_line_length: usize,
+    pub room_capacity: usize,
+    pub replay_length: usize,
+    pub backfill: usize,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Tls {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Log {
+    pub level: LevelFilter,
+    pub file: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: vec![IpAddr::V4(Ipv4Addr::UNSPECIFIED)],
+            port: DEFAULT_PORT,
+            users_db: crate::users::DEFAULT_USERS_DB.into(),
+            history_log: crate::history::DEFAULT_HISTORY_LOG.into(),
+            motd: None,
+            admins: vec![],
+            limits: Limits::default(),
+            tls: None,
+            log: Log::default(),
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_line_length: DEFAULT_MAX_LINE_LENGTH,
+            room_capacity: crate::rooms::DEFAULT_ROOM_CAPACITY,
+            replay_length: crate::history::DEFAULT_REPLAY_LENGTH,
+            backfill: crate::handler::DEFAULT_BACKFILL,
+        }
+    }
+}
+
+impl Default for Log {
+    fn default() -> Self {
+        Self {
+            level: LevelFilter::Info,
+            file: None,
+        }
+    }
+}
+
+impl Config {
+    /// Reads the file `cli` points at (if any), applies the flags and checks the result.
+    pub fn load(cli: &Cli) -> Result<Self> {
+        let mut config = match &cli.config {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+        config.apply(cli)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("can't read config file {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("invalid config file {}", path.display()))
+    }
+
+    fn apply(&mut self, cli: &Cli) -> Result<()> {
+        if !cli.bind.is_empty() {
+            self.bind = cli.bind.clone();
+        }
+        if !cli.admins.is_empty() {
+            self.admins = cli.admins.clone();
+        }
+        set(&mut self.port, &cli.port);
+        set(&mut self.users_db, &cli.users_db);
+        set(&mut self.history_log, &cli.history_log);
+        set(&mut self.limits.max_line_length, &cli.max_line_length);
+        set(&mut self.limits.room_capacity, &cli.room_capacity);
+        set(&mut self.limits.replay_length, &cli.replay_length);
+        set(&mut self.limits.backfill, &cli.backfill);
+        set(&mut self.log.level, &cli.log_level);
+        if cli.motd.is_some() {
+            self.motd = cli.motd.clone();
+        }
+        if cli.log_file.is_some() {
+            self.log.file = cli.log_file.clone();
+        }
+        match (&cli.tls_cert, &cli.tls_key) {
+            (Some(cert), Some(key)) => {
+                self.tls = Some(Tls {
+                    cert: cert.clone(),
+                    key: key.clone(),
+                })
+            }
+            (None, None) => {}
+            _ => anyhow::bail!("--tls-cert and --tls-key have to be given together"),
+        }
+        Ok(())
+    }
+
+    /// Checks what serde can't, so mistakes surface on startup.
+    pub fn validate(&self) -> Result<()> {
+        if self.bind.is_empty() {
+            anyhow::bail!("bind: give at least one address to listen on");
+        }
+        if self.limits.max_line_length < MIN_MAX_LINE_LENGTH {
+            anyhow::bail!("limits.max_line_length: has to be at least {MIN_MAX_LINE_LENGTH}");
+        }
+        // half a frame for the text, the rest for the JSON around it
+        let max_text = chat_proto::MAX_FRAME_LENGTH / 2;
+        if self.limits.max_line_length > max_text {
+            anyhow::bail!(
+                "limits.max_line_length: has to be at most {max_text}, so a message fits in one frame"
+            );
+        }
+        if self.limits.room_capacity == 0 {
+            anyhow::bail!("limits.room_capacity: has to be at least 1");
+        }
+        let keep = crate::history::KEEP_PER_ROOM;
+        if self.limits.replay_length > keep {
+            anyhow::bail!("limits.replay_length: at most {keep} messages are kept per room");
+        }
+        if self.limits.replay_length * self.limits.max_line_length > max_text {
+            anyhow::bail!(
+                "limits.replay_length: times limits.max_line_length has to be at most {max_text}, so a replay fits in one frame"
+            );
+        }
+        if self
+            .motd
+            .as_ref()
+            .is_some_and(|motd| motd.contains(['\n', '\r']))
+        {
+            anyhow::bail!("motd: has to be a single line");
+        }
+        Ok(())
+    }
+}
+
+fn set<T: Clone>(target: &mut T, value: &Option<T>) {
+    if let Some(value) = value {
+        *target = value.clone();
+    }
+}
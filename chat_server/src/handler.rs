@@ -1,75 +1,435 @@
-use crate::users::try_to_login;
+use crate::commands::Context;
+use crate::history::Entry;
+use crate::rooms::{Lags, DEFAULT_ROOM};
+use crate::sessions::Session;
+use crate::state::State;
 use anyhow::Result;
+use chat_proto::{split_lines, FrameError, LineCodec, Message, Timestamp, MAX_FRAME_LENGTH};
+use std::{net::SocketAddr, sync::Arc, time::Instant};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-    sync::broadcast::Sender,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::broadcast::{error::RecvError, Receiver, Sender},
 };
 
-pub const MAX_LINE_LENGTH: usize = 1024;
-pub const LOGIN_PROMPT: &str = "Please enter [username]:[password]\n";
-pub const WELCOME_MSG: &str = "Welcome to the chat!\n";
-pub const BAD_LOGIN_MSG: &str = "Wrong username or password\n";
-pub const SYSTEM_MSG_PREF: &str = "SYSTEM:";
+const READ_BUFFER_LENGTH: usize = 1024;
+pub const LOGIN_PROMPT: &str =
+    "Please enter [username]:[password] or register [username]:[password]";
+pub const REGISTER_CMD: &str = "register ";
+pub const WELCOME_MSG: &str = "Welcome to the chat!";
+pub const BAD_LOGIN_MSG: &str = "Wrong username or password";
+pub const KICK_CMD: &str = "kick ";
+/// Like `kick`, but also goes back to the room left last and replays what was missed since.
+pub const RESUME_CMD: &str = "resume ";
+pub const ALREADY_LOGGED_IN_MSG: &str =
+    "You are already logged in elsewhere, use kick [username]:[password] to end that session";
+pub const KICKED_MSG: &str = "You were logged out because your account logged in elsewhere";
+pub const SHUTDOWN_MSG: &str = "The server is shutting down, see you later!";
+pub const TOO_SLOW_MSG: &str = "You were disconnected for falling behind the chat too often";
+/// At most this many missed messages are resent to a client that fell behind or resumed.
+pub const DEFAULT_BACKFILL: usize = 100;
 
-fn get_time() -> String {
-    // for testing purposes
-    if cfg!(debug_assertions) {
-        return "00:00".to_string();
+/// A replay of `messages` in `room`, leaving out the oldest if that's what it takes for
+/// clients to read it in one frame.
+pub fn history_msg(room: &str, mut messages: Vec<Message>) -> Message {
+    let empty = Message::History {
+        room: room.to_string(),
+        messages: vec![],
+    };
+    let mut length = empty.encode().len();
+    // on their own, messages carry a version too, so this overestimates a little
+    let fits = messages
+        .iter()
+        .rev()
+        .take_while(|msg| {
+            length += msg.encode().len() + 1;
+            length <= MAX_FRAME_LENGTH
+        })
+        .count();
+    messages.drain(..messages.len() - fits);
+    Message::History {
+        room: room.to_string(),
+        messages,
     }
-    chrono::Utc::now().format("%H:%M").to_string()
 }
-
-pub fn system_msg(msg: &str) -> String {
-    format!("{SYSTEM_MSG_PREF} [{}] {msg}\n", get_time())
+pub fn system_msg(time: Timestamp, msg: &str) -> Message {
+    Message::System {
+        time,
+        text: msg.to_string(),
+    }
+}
+pub fn normal_msg(time: Timestamp, uname: &str, msg: &str) -> Message {
+    Message::Chat {
+        time,
+        from: uname.to_string(),
+        text: msg.to_string(),
+    }
+}
+pub fn action_msg(time: Timestamp, uname: &str, msg: &str) -> Message {
+    Message::Action {
+        time,
+        from: uname.to_string(),
+        text: msg.to_string(),
+    }
+}
+pub fn direct_msg(time: Timestamp, from: &str, to: &str, msg: &str) -> Message {
+    Message::Direct {
+        time,
+        from: from.to_string(),
+        to: to.to_string(),
+        text: msg.to_string(),
+    }
+}
+pub fn error_msg(time: Timestamp, msg: &str) -> Message {
+    Message::Error {
+        time,
+        text: msg.to_string(),
+    }
 }
-pub fn normal_msg(uname: &str, msg: &str) -> String {
-    format!("[{}] {uname}: {msg}\n", get_time())
+pub fn bye_msg(time: Timestamp, msg: &str) -> Message {
+    Message::Bye {
+        time,
+        text: msg.to_string(),
+    }
+}
+pub fn join_msg(time: Timestamp, uname: &str, room: &str) -> Message {
+    Message::Join {
+        time,
+        user: uname.to_string(),
+        room: room.to_string(),
+    }
+}
+pub fn leave_msg(time: Timestamp, uname: &str, room: &str) -> Message {
+    Message::Leave {
+        time,
+        user: uname.to_string(),
+        room: room.to_string(),
+    }
+}
+pub fn room_msg(time: Timestamp, room: &str) -> Message {
+    Message::Room {
+        time,
+        name: room.to_string(),
+    }
+}
+pub fn frame_error_msg(time: Timestamp, e: &FrameError, max_len: usize) -> Message {
+    match e {
+        FrameError::TooLong => error_msg(
+            time,
+            &format!("Message dropped: longer than {max_len} bytes"),
+        ),
+        FrameError::InvalidUtf8 => error_msg(time, "Message dropped: not valid UTF-8"),
+    }
 }
 
-pub async fn handler(mut socket: TcpStream, tx: Sender<String>) -> Result<()> {
-    let mut buf = [0u8; MAX_LINE_LENGTH];
+/// Anything a client can be connected through, a plain TCP socket or a TLS stream over one.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin {}
+impl<S: AsyncRead + AsyncWrite + Unpin> Stream for S {}
 
-    // Phase 1: Verification
-    socket
-        .write_all(system_msg(LOGIN_PROMPT).as_bytes())
-        .await?;
-    let uname: String;
+async fn send(socket: &mut impl Stream, msg: &Message) -> Result<()> {
+    socket.write_all(&LineCodec::encode(&msg.encode())).await?;
+    Ok(())
+}
+
+/// Waits for the next complete line from the client, `None` once it disconnects.
+///
+/// Cancel safe: bytes already read are kept in `codec`.
+async fn next_line(
+    socket: &mut impl Stream,
+    codec: &mut LineCodec,
+) -> Result<Option<Result<String, FrameError>>> {
+    let mut buf = [0u8; READ_BUFFER_LENGTH];
     loop {
+        if let Some(frame) = codec.next_frame() {
+            return Ok(Some(frame));
+        }
         let n = socket.read(&mut buf).await?;
         if n == 0 {
-            return Ok(());
+            return Ok(None);
         }
-        let msg = String::from_utf8_lossy(&buf[..n]).trim().to_string();
-
-        if let Some(u) = try_to_login(msg) {
-            uname = u;
-            socket.write_all(system_msg(WELCOME_MSG).as_bytes()).await?;
-            break;
-        } else {
-            socket
-                .write_all(system_msg(BAD_LOGIN_MSG).as_bytes())
-                .await?;
+        codec.feed(&buf[..n]);
+    }
+}
+
+pub fn broadcast(state: &State, tx: &Sender<Arc<Entry>>, room: &str, msg: Message) {
+    log::debug!("[#{room}]: {:?}", msg);
+    state.history.record(tx, room, msg);
+}
+
+/// What a client gets replayed when entering a room.
+#[derive(Clone, Copy)]
+enum Replay {
+    /// The last this many messages.
+    Last(usize),
+    /// Everything after the entry with this id, up to `limits.backfill` messages.
+    Since(u64),
+}
+
+/// The room a logged in user is currently talking in.
+pub struct CurrentRoom {
+    pub name: String,
+    pub tx: Sender<Arc<Entry>>,
+    rx: Receiver<Arc<Entry>>,
+    /// Id of the newest entry this client already got, replays included.
+    last_seen: Option<u64>,
+}
+
+impl CurrentRoom {
+    /// Joins `name`, also returning the messages from its history that `replay` asks for.
+    fn enter(state: &State, uname: &str, name: &str, replay: Replay) -> (Self, Vec<Message>) {
+        // subscribe before looking at the history, so nothing falls in between
+        let (tx, rx) = state.rooms.join(name);
+        // anything older than the join counts as seen, replayed or not
+        let (entries, last_seen) = match replay {
+            Replay::Last(n) => {
+                let entries = state.history.last(name, n.max(1));
+                let last_seen = entries.last().map(|e| e.id);
+                let skip = entries.len().saturating_sub(n);
+                (entries[skip..].to_vec(), last_seen)
+            }
+            Replay::Since(id) => {
+                let entries = state.history.since(name, Some(id));
+                let last_seen = entries.last().map_or(id, |e| e.id);
+                let skip = entries.len().saturating_sub(state.config.limits.backfill);
+                (entries[skip..].to_vec(), Some(last_seen))
+            }
+        };
+        let room = Self {
+            name: name.to_string(),
+            tx,
+            rx,
+            last_seen,
+        };
+        broadcast(state, &room.tx, name, join_msg(state.now(), uname, name));
+        (room, entries.iter().map(|e| e.msg.clone()).collect())
+    }
+
+    pub fn switch(&mut self, state: &State, uname: &str, target: &str) {
+        let (room, _) = CurrentRoom::enter(state, uname, target, Replay::Last(0));
+        let old = std::mem::replace(self, room);
+        old.leave(state, uname);
+    }
+
+    fn leave(self, state: &State, uname: &str) {
+        let CurrentRoom { name, tx, rx, .. } = self;
+        drop(rx);
+        broadcast(state, &tx, &name, leave_msg(state.now(), uname, &name));
+        state.rooms.cleanup(&name);
+    }
+
+    /// Whether `entry` is new to this client, i.e. wasn't replayed already.
+    fn is_new(&mut self, entry: &Entry) -> bool {
+        if self.last_seen.is_some_and(|id| entry.id <= id) {
+            return false;
         }
+        self.last_seen = Some(entry.id);
+        true
     }
+}
 
-    // Phase 2: Proxy messages to other clients
-    let mut rx = tx.subscribe();
-    tx.send(system_msg(format!("{uname} logged in\n").as_str()))?;
+/// Tells a client that fell `missed` messages behind, and resends what the history still has
+/// unless backfill is turned off.
+async fn catch_up(
+    socket: &mut impl Stream,
+    state: &State,
+    room: &mut CurrentRoom,
+    missed: u64,
+) -> Result<()> {
+    let text = format!(
+        "You fell behind and missed {missed} messages in #{}",
+        room.name
+    );
+    send(socket, &system_msg(state.now(), &text)).await?;
+    let max = state.config.limits.backfill;
+    if max == 0 {
+        return Ok(());
+    }
+    let mut entries = state.history.since(&room.name, room.last_seen);
+    let Some(newest) = entries.last() else {
+        return Ok(());
+    };
+    room.last_seen = Some(newest.id);
+    let skip = entries.len().saturating_sub(max);
+    let backfill = history_msg(
+        &room.name,
+        entries.drain(skip..).map(|e| e.msg.clone()).collect(),
+    );
+    send(socket, &backfill).await
+}
+
+/// Runs one line from a logged in user, either a command or a chat message.
+///
+/// Returns false once the user asked to leave.
+async fn handle_line(
+    socket: &mut impl Stream,
+    state: &State,
+    uname: &str,
+    nick: &mut String,
+    room: &mut CurrentRoom,
+    line: &str,
+) -> Result<bool> {
+    if line.is_empty() {
+        return Ok(true);
+    }
+    let mut ctx = Context {
+        state,
+        uname,
+        nick,
+        room,
+        replies: vec![],
+        quit: false,
+    };
+    if !state.commands.run(&mut ctx, line) {
+        // `//` stands for a single leading slash
+        let text = line.strip_prefix('/').unwrap_or(line);
+        let msg = normal_msg(state.now(), ctx.nick, text);
+        broadcast(state, &ctx.room.tx, &ctx.room.name, msg);
+    }
+    for reply in &ctx.replies {
+        send(socket, reply).await?;
+    }
+    Ok(!ctx.quit)
+}
 
+pub async fn handler(mut socket: impl Stream, peer: SocketAddr, state: Arc<State>) -> Result<()> {
+    let max_len = state.config.limits.max_line_length;
+    let mut codec = LineCodec::new(max_len);
+
+    // Phase 1: Verification
+    let (session, mut direct_rx) = Session::new();
+    send(&mut socket, &system_msg(state.now(), LOGIN_PROMPT)).await?;
+    let uname: String;
+    let resume: bool;
     loop {
-        tokio::select! {
-            Ok(msg) = rx.recv() => {
-                socket.write_all(msg.as_bytes()).await?
+        let frame = tokio::select! {
+            frame = next_line(&mut socket, &mut codec) => frame?,
+            _ = state.shutting_down() => return send(&mut socket, &system_msg(state.now(), SHUTDOWN_MSG)).await,
+        };
+        let msg = match frame {
+            None => return Ok(()),
+            Some(Ok(msg)) => msg.trim().to_string(),
+            Some(Err(e)) => {
+                send(&mut socket, &frame_error_msg(state.now(), &e, max_len)).await?;
+                continue;
+            }
+        };
+
+        let kick = msg.starts_with(KICK_CMD);
+        let resuming = msg.starts_with(RESUME_CMD);
+        let registering = msg.starts_with(REGISTER_CMD);
+        let credentials = [REGISTER_CMD, KICK_CMD, RESUME_CMD]
+            .iter()
+            .find_map(|cmd| msg.strip_prefix(cmd))
+            .unwrap_or(&msg)
+            .trim()
+            .to_string();
+        // hashing takes tens of milliseconds of CPU, too long to hold up a worker
+        let users = state.clone();
+        let login = tokio::task::spawn_blocking(move || {
+            if registering {
+                users.users.register(&credentials)
+            } else {
+                users.users.try_to_login(&credentials)
             }
-            Ok(n) = socket.read(&mut buf) => {
-                if n == 0 {
-                    tx.send(system_msg(format!("{uname} logged out\n").as_str()))?;
-                    return Ok(());
+        })
+        .await?
+        .map_err(|e| {
+            if registering {
+                error_msg(state.now(), &format!("Registration failed: {e}"))
+            } else {
+                error_msg(state.now(), BAD_LOGIN_MSG)
+            }
+        });
+
+        match login {
+            Err(reply) => send(&mut socket, &reply).await?,
+            Ok(u) if kick || resuming => {
+                state.sessions.take_over(&u, session.clone());
+                uname = u;
+                resume = resuming;
+                break;
+            }
+            Ok(u) if state.sessions.claim(&u, session.clone()) => {
+                uname = u;
+                resume = false;
+                break;
+            }
+            Ok(u) => {
+                send(&mut socket, &error_msg(state.now(), ALREADY_LOGGED_IN_MSG)).await?;
+                let warning = format!("Someone tried to log in as you from {peer}");
+                state
+                    .sessions
+                    .send_to(&u, system_msg(state.now(), &warning));
+            }
+        }
+    }
+
+    // Phase 2: Proxy messages to other clients
+    state.sessions.broadcast_roster();
+    let (name, replay) = match resume.then(|| state.history.last_leave(&uname)).flatten() {
+        Some((name, left)) => (name, Replay::Since(left)),
+        None => (
+            DEFAULT_ROOM.to_string(),
+            Replay::Last(state.config.limits.replay_length),
+        ),
+    };
+    let (mut room, replay) = CurrentRoom::enter(&state, &uname, &name, replay);
+
+    let mut lags = Lags::default();
+    let mut nick = uname.clone();
+    let result: Result<()> = async {
+        // our own join waits in `room.rx` until the replay is out
+        send(&mut socket, &system_msg(state.now(), WELCOME_MSG)).await?;
+        if let Some(motd) = &state.config.motd {
+            send(&mut socket, &system_msg(state.now(), motd)).await?;
+        }
+        send(&mut socket, &room_msg(state.now(), &room.name)).await?;
+        send(&mut socket, &history_msg(&room.name, replay)).await?;
+
+        loop {
+            tokio::select! {
+                entry = room.rx.recv() => match entry {
+                    Ok(entry) => {
+                        if room.is_new(&entry) {
+                            send(&mut socket, &entry.msg).await?
+                        }
+                    }
+                    Err(RecvError::Lagged(_)) if lags.lagged(Instant::now()) => {
+                        return send(&mut socket, &error_msg(state.now(), TOO_SLOW_MSG)).await
+                    }
+                    Err(RecvError::Lagged(missed)) => {
+                        catch_up(&mut socket, &state, &mut room, missed).await?
+                    }
+                    // can't happen while `room` holds a sender, but don't spin if it does
+                    Err(RecvError::Closed) => anyhow::bail!("#{} was closed", room.name),
+                },
+                Some(msg) = direct_rx.recv() => send(&mut socket, &msg).await?,
+                _ = session.kicked() => return send(&mut socket, &bye_msg(state.now(), KICKED_MSG)).await,
+                _ = state.shutting_down() => {
+                    return send(&mut socket, &system_msg(state.now(), SHUTDOWN_MSG)).await
+                }
+                frame = next_line(&mut socket, &mut codec) => match frame? {
+                    None => return Ok(()),
+                    Some(Ok(msg)) => {
+                        let msg = split_lines(&msg);
+                        let line = msg.trim();
+                        if !handle_line(&mut socket, &state, &uname, &mut nick, &mut room, line)
+                            .await?
+                        {
+                            return Ok(());
+                        }
+                    }
+                    Some(Err(e)) => send(&mut socket, &frame_error_msg(state.now(), &e, max_len)).await?,
                 }
-                let msg = String::from_utf8_lossy(&buf[..n]).trim().to_string();
-                tx.send(normal_msg(&uname, &msg))?;
             }
         }
     }
+    .await;
+
+    // also runs when the connection broke, so nobody is left thinking we're online
+    state.sessions.remove(&uname, &session);
+    state.sessions.broadcast_roster();
+    room.leave(&state, &uname);
+    // lets TLS clients tell a clean close from a cut connection
+    let _ = socket.shutdown().await;
+    result
 }
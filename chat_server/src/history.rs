@@ -0,0 +1,139 @@
+use anyhow::Result;
+use chat_proto::Message;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::broadcast::Sender;
+
+pub const DEFAULT_HISTORY_LOG: &str = "history.log";
+/// How many messages a client gets replayed right after logging in, unless configured.
+pub const DEFAULT_REPLAY_LENGTH: usize = 20;
+/// How many messages per room are kept in memory for replays.
+pub const KEEP_PER_ROOM: usize = 500;
+
+/// A recorded room event. Ids grow by one with every entry, across all rooms.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    pub id: u64,
+    pub room: String,
+    pub msg: Message,
+}
+
+struct Log {
+    file: File,
+    next_id: u64,
+    rooms: HashMap<String, VecDeque<Arc<Entry>>>,
+}
+
+/// Append-only log of everything said in rooms, one JSON entry per line.
+pub struct History {
+    path: PathBuf,
+    log: Mutex<Log>,
+}
+
+impl History {
+    /// Opens the log at `path`, loading the tail of every room.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let mut next_id = 0;
+        let mut rooms: HashMap<String, VecDeque<Arc<Entry>>> = HashMap::new();
+        match fs::read_to_string(&path) {
+            Ok(content) => {
+                for (i, line) in content.lines().enumerate() {
+                    let entry: Entry = serde_json::from_str(line).map_err(|e| {
+                        anyhow::anyhow!("{}:{}: bad history entry: {e}", path.display(), i + 1)
+                    })?;
+                    next_id = next_id.max(entry.id + 1);
+                    let room = rooms.entry(entry.room.clone()).or_default();
+                    if room.len() == KEEP_PER_ROOM {
+                        room.pop_front();
+                    }
+                    room.push_back(Arc::new(entry));
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Self {
+            path,
+            log: Mutex::new(Log {
+                file,
+                next_id,
+                rooms,
+            }),
+        })
+    }
+
+    /// Records `msg` and broadcasts it to `room` in one step, so ids and delivery order agree.
+    pub fn record(&self, tx: &Sender<Arc<Entry>>, room: &str, msg: Message) {
+        let mut log = self.log.lock().unwrap();
+        let entry = Arc::new(Entry {
+            id: log.next_id,
+            room: room.to_string(),
+            msg,
+        });
+        log.next_id += 1;
+
+        let line = serde_json::to_string(entry.as_ref()).expect("entries always serialize");
+        if let Err(e) = writeln!(log.file, "{line}") {
+            // losing history is bad, losing the chat because of it would be worse
+            log::error!("Couldn't write to {}: {e}", self.path.display());
+        }
+        let kept = log.rooms.entry(room.to_string()).or_default();
+        if kept.len() == KEEP_PER_ROOM {
+            kept.pop_front();
+        }
+        kept.push_back(entry.clone());
+
+        // an empty room is not an error, the message just goes nowhere
+        let _ = tx.send(entry);
+    }
+
+    /// Entries of `room` newer than `after` that are still kept, oldest first.
+    pub fn since(&self, room: &str, after: Option<u64>) -> Vec<Arc<Entry>> {
+        let log = self.log.lock().unwrap();
+        let Some(kept) = log.rooms.get(room) else {
+            return vec![];
+        };
+        kept.iter()
+            .filter(|e| after.is_none_or(|id| e.id > id))
+            .cloned()
+            .collect()
+    }
+
+    /// Room and entry id of the last time `user` left a room, if that's still kept.
+    pub fn last_leave(&self, user: &str) -> Option<(String, u64)> {
+        let log = self.log.lock().unwrap();
+        log.rooms
+            .values()
+            .flatten()
+            .filter(|e| matches!(&e.msg, Message::Leave { user: u, .. } if u == user))
+            .max_by_key(|e| e.id)
+            .map(|e| (e.room.clone(), e.id))
+    }
+
+    /// Makes sure everything recorded so far is on disk.
+    pub fn flush(&self) -> Result<()> {
+        let log = self.log.lock().unwrap();
+        log.file.sync_all()?;
+        Ok(())
+    }
+
+    /// The last `n` entries of `room`, oldest first.
+    pub fn last(&self, room: &str, n: usize) -> Vec<Arc<Entry>> {
+        let log = self.log.lock().unwrap();
+        let Some(kept) = log.rooms.get(room) else {
+            return vec![];
+        };
+        kept.iter()
+            .skip(kept.len().saturating_sub(n))
+            .cloned()
+            .collect()
+    }
+}
@@ -0,0 +1,1075 @@
+//! The chat server, as a library so tests can run it in-process.
+
+use state::State;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::{net::TcpListener, task::JoinSet};
+use tokio_rustls::TlsAcceptor;
+
+pub mod clock;
+pub mod commands;
+pub mod config;
+pub mod handler;
+pub mod history;
+pub mod rooms;
+pub mod sessions;
+pub mod state;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod tls;
+pub mod users;
+
+/// How long clients get to disconnect once the server is shutting down.
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to wait before accepting again after accepting failed.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Accepts clients until shutdown, handing each one to its own task, then waits for them to leave.
+pub async fn serve(listener: TcpListener, state: Arc<State>, tls: Option<TlsAcceptor>) {
+    let mut clients = JoinSet::new();
+    loop {
+        let (socket, addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // usually out of file descriptors, which passes as clients leave
+                    log::error!("Can't accept a connection: {e}");
+                    tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                    continue;
+                }
+            },
+            _ = state.shutting_down() => break,
+        };
+        // don't keep the results of everyone who ever connected around
+        while clients.try_join_next().is_some() {}
+
+        let state = state.clone();
+        let tls = tls.clone();
+
+        clients.spawn(async move {
+            log::info!("{} connected", addr);
+            let result = match tls {
+                Some(tls) => match tls.accept(socket).await {
+                    Ok(socket) => handler::handler(socket, addr, state).await,
+                    Err(e) => Err(e.into()),
+                },
+                None => handler::handler(socket, addr, state).await,
+            };
+            match result {
+                Ok(_) => log::info!("{} disconnected", addr),
+                Err(e) => log::warn!("{} error: {:?}", addr, e),
+            }
+        });
+    }
+    drop(listener);
+
+    let all_gone = async { while clients.join_next().await.is_some() {} };
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, all_gone)
+        .await
+        .is_err()
+    {
+        // dropping the set aborts whoever is left
+        log::warn!("{} clients didn't disconnect in time", clients.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::FixedClock;
+    use crate::config::{Cli, Command, Config};
+    use crate::handler::*;
+    use crate::history::History;
+    use crate::testing::{TestServer, T0};
+    use crate::users::UserDb;
+    use chat_proto::Message;
+    use clap::Parser;
+
+    use super::*;
+
+    #[test]
+    fn test_login_msg() {
+        let server = TestServer::start();
+        let mut socket = server.connect();
+        assert_eq!(socket.read(), system_msg(T0, LOGIN_PROMPT));
+    }
+
+    #[test]
+    fn wrong_login() {
+        let server = TestServer::start();
+        let mut socket = server.connect();
+        socket.read(); // skip login prompt
+        socket.send_line("wrong:123:543");
+        assert_eq!(socket.read(), error_msg(T0, BAD_LOGIN_MSG));
+        socket.send_line("aaaaaaaaaaaaaaaaaaaaaaaaaaa:123456");
+        assert_eq!(socket.read(), error_msg(T0, BAD_LOGIN_MSG));
+        socket.send_line("aa:a:123456");
+        assert_eq!(socket.read(), error_msg(T0, BAD_LOGIN_MSG));
+        socket.send_line("nobody:123456");
+        assert_eq!(socket.read(), error_msg(T0, BAD_LOGIN_MSG));
+        socket.send_line("piotrek:654321");
+        assert_eq!(socket.read(), error_msg(T0, BAD_LOGIN_MSG));
+    }
+
+    #[test]
+    fn correct_login() {
+        let server = TestServer::start();
+        let mut socket = server.connect();
+        socket.read(); // skip login prompt
+        socket.send_line("kasia:wrong");
+        assert_eq!(socket.read(), error_msg(T0, BAD_LOGIN_MSG));
+        socket.send_line("piotrek:123456");
+        assert_eq!(socket.read(), system_msg(T0, WELCOME_MSG));
+        assert_eq!(socket.read(), room_msg(T0, "lobby"));
+        socket.read_history();
+        assert_eq!(socket.read(), join_msg(T0, "piotrek", "lobby"));
+    }
+
+    #[test]
+    fn register_then_login() {
+        let server = TestServer::start();
+        let uname = "ola";
+        let mut socket = server.connect();
+        socket.read(); // skip login prompt
+        socket.send_line("register piotrek:123456");
+        assert_eq!(
+            socket.read(),
+            error_msg(T0, "Registration failed: username already taken")
+        );
+        socket.send_line("register bad name:123456");
+        assert_eq!(
+            socket.read(),
+            error_msg(
+                T0,
+                &format!(
+                    "Registration failed: {}",
+                    users::LoginError::InvalidUsername
+                )
+            )
+        );
+        socket.send_line(&format!("register {uname}:123"));
+        assert_eq!(
+            socket.read(),
+            error_msg(
+                T0,
+                &format!(
+                    "Registration failed: {}",
+                    users::LoginError::PasswordTooShort
+                )
+            )
+        );
+        socket.send_line(&format!("register {uname}:secret123"));
+        assert_eq!(socket.read(), system_msg(T0, WELCOME_MSG));
+        assert_eq!(socket.read(), room_msg(T0, "lobby"));
+        socket.read_history();
+        assert_eq!(socket.read(), join_msg(T0, uname, "lobby"));
+        drop(socket);
+
+        let mut socket = server.connect();
+        socket.read(); // skip login prompt
+        socket.send_line(&format!("{uname}:secret123"));
+        assert_eq!(socket.read(), system_msg(T0, WELCOME_MSG));
+    }
+
+    #[test]
+    fn test_broadcast() {
+        let server = TestServer::start();
+        let mut socket_1 = server.login("piotrek");
+        let mut socket_2 = server.login("kasia");
+        assert_eq!(socket_1.read(), join_msg(T0, "kasia", "lobby"));
+
+        socket_1.send_line("Hello!");
+        assert_eq!(socket_1.read(), normal_msg(T0, "piotrek", "Hello!"));
+        assert_eq!(socket_2.read(), normal_msg(T0, "piotrek", "Hello!"));
+        socket_2.send_line("Hi!");
+        assert_eq!(socket_1.read(), normal_msg(T0, "kasia", "Hi!"));
+        assert_eq!(socket_2.read(), normal_msg(T0, "kasia", "Hi!"));
+
+        drop(socket_1);
+        assert_eq!(socket_2.read(), leave_msg(T0, "piotrek", "lobby"));
+    }
+
+    #[test]
+    fn multi_line_messages() {
+        let server = TestServer::start();
+        let mut socket = server.login("piotrek");
+        let frame = chat_proto::join_lines("\nfirst line\n  indented\n\n");
+        socket.send_line(&frame);
+        assert_eq!(
+            socket.read(),
+            normal_msg(T0, "piotrek", "first line\n  indented")
+        );
+        socket.send_line(&chat_proto::join_lines("/me waves\ntwice"));
+        assert_eq!(socket.read(), action_msg(T0, "piotrek", "waves\ntwice"));
+    }
+
+    #[test]
+    fn fragmented_and_batched_writes() {
+        let server = TestServer::start();
+        let mut socket = server.connect();
+        socket.read(); // skip login prompt
+        for part in ["pio", "trek:12", "3456\n"] {
+            socket.send(part);
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        assert_eq!(socket.read(), system_msg(T0, WELCOME_MSG));
+        socket.read(); // skip room msg
+        socket.read_history();
+        socket.read(); // skip join msg
+
+        socket.send("first\nsecond\n");
+        assert_eq!(socket.read(), normal_msg(T0, "piotrek", "first"));
+        assert_eq!(socket.read(), normal_msg(T0, "piotrek", "second"));
+
+        let long = "a".repeat(config::DEFAULT_MAX_LINE_LENGTH * 2);
+        socket.send(format!("{long}\nafter\n"));
+        assert_eq!(
+            socket.read(),
+            frame_error_msg(
+                T0,
+                &chat_proto::FrameError::TooLong,
+                config::DEFAULT_MAX_LINE_LENGTH
+            )
+        );
+        assert_eq!(socket.read(), normal_msg(T0, "piotrek", "after"));
+    }
+
+    #[test]
+    fn utf8_split_across_writes() {
+        let server = TestServer::start();
+        let mut socket = server.login("kasia");
+
+        let text = "zażółć gęślą jaźń 🦀 你好";
+        let bytes = format!("{text}\n").into_bytes();
+        // cut inside "ż", inside the crab and inside "你"
+        let crab = text.find('🦀').unwrap();
+        let ni = text.find('你').unwrap();
+        for part in [
+            &bytes[..3],
+            &bytes[3..crab + 2],
+            &bytes[crab + 2..ni + 1],
+            &bytes[ni + 1..],
+        ] {
+            socket.send(part);
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        assert_eq!(socket.read(), normal_msg(T0, "kasia", text));
+    }
+
+    #[test]
+    fn rooms_join_part_list() {
+        let server = TestServer::start();
+        let mut socket_1 = server.login("piotrek");
+        let mut socket_2 = server.login("kasia");
+        socket_1.read(); // skip kasia's join
+
+        socket_1.send_line("/join #rust");
+        assert_eq!(socket_1.read(), room_msg(T0, "rust"));
+        assert_eq!(socket_1.read(), join_msg(T0, "piotrek", "rust"));
+        assert_eq!(socket_2.read(), leave_msg(T0, "piotrek", "lobby"));
+
+        // messages stay inside their room
+        socket_1.send_line("anyone here?");
+        assert_eq!(socket_1.read(), normal_msg(T0, "piotrek", "anyone here?"));
+        socket_2.send_line("lobby talk");
+        assert_eq!(socket_2.read(), normal_msg(T0, "kasia", "lobby talk"));
+
+        socket_2.send_line("/list");
+        assert_eq!(
+            socket_2.read(),
+            system_msg(T0, "Rooms: #lobby (1), #rust (1)")
+        );
+        socket_2.send_line("/join rust");
+        assert_eq!(socket_2.read(), room_msg(T0, "rust"));
+        assert_eq!(socket_2.read(), join_msg(T0, "kasia", "rust"));
+        assert_eq!(socket_1.read(), join_msg(T0, "kasia", "rust"));
+
+        socket_2.send_line("/join #rust");
+        assert_eq!(socket_2.read(), error_msg(T0, "You are already in #rust"));
+        socket_2.send_line("/join #no spaces");
+        assert_eq!(socket_2.read(), error_msg(T0, "Usage: /join #room"));
+
+        socket_1.send_line("/part");
+        assert_eq!(socket_1.read(), room_msg(T0, "lobby"));
+        assert_eq!(socket_1.read(), join_msg(T0, "piotrek", "lobby"));
+        assert_eq!(socket_2.read(), leave_msg(T0, "piotrek", "rust"));
+        socket_1.send_line("/part");
+        assert_eq!(socket_1.read(), error_msg(T0, "You can't leave #lobby"));
+
+        // the last one out removes the room
+        socket_2.send_line("/part");
+        assert_eq!(socket_2.read(), room_msg(T0, "lobby"));
+        assert_eq!(socket_2.read(), join_msg(T0, "kasia", "lobby"));
+        assert_eq!(socket_1.read(), join_msg(T0, "kasia", "lobby"));
+        socket_1.send_line("/list");
+        assert_eq!(socket_1.read(), system_msg(T0, "Rooms: #lobby (2)"));
+    }
+
+    #[test]
+    fn direct_messages() {
+        let server = TestServer::start();
+        let mut socket_1 = server.login("piotrek");
+        let mut socket_2 = server.login("kasia");
+        let mut socket_3 = server.login("ptr");
+        socket_1.read(); // skip kasia's join
+        socket_1.read(); // skip ptr's join
+        socket_2.read(); // skip ptr's join
+
+        socket_1.send_line("/msg kasia  psst, over here");
+        let dm = direct_msg(T0, "piotrek", "kasia", "psst, over here");
+        assert_eq!(socket_1.read(), dm);
+        assert_eq!(socket_2.read(), dm);
+        // ptr's next message is their own, the DM never reached them
+        socket_3.send_line("hi");
+        assert_eq!(socket_3.read(), normal_msg(T0, "ptr", "hi"));
+        socket_1.read(); // skip ptr's hi
+        socket_2.read(); // skip ptr's hi
+
+        // DMs follow people into other rooms
+        socket_2.send_line("/join secret");
+        socket_2.read(); // skip room msg
+        socket_2.read(); // skip join msg
+        socket_1.read(); // skip kasia's leave
+        socket_2.send_line("/msg piotrek found me?");
+        let dm = direct_msg(T0, "kasia", "piotrek", "found me?");
+        assert_eq!(socket_2.read(), dm);
+        assert_eq!(socket_1.read(), dm);
+
+        // nicknames work as well as usernames, and DMs come from whoever you go by
+        socket_1.send_line("/nick Piotr");
+        assert_eq!(
+            socket_1.read(),
+            system_msg(T0, "piotrek is now known as Piotr")
+        );
+        socket_2.send_line("/msg Piotr by nick");
+        let dm = direct_msg(T0, "kasia", "Piotr", "by nick");
+        assert_eq!(socket_2.read(), dm);
+        assert_eq!(socket_1.read(), dm);
+        socket_1.send_line("/msg kasia signed Piotr");
+        let dm = direct_msg(T0, "Piotr", "kasia", "signed Piotr");
+        assert_eq!(socket_1.read(), dm);
+        assert_eq!(socket_2.read(), dm);
+        socket_1.send_line("/msg piotrek note to self");
+        let dm = direct_msg(T0, "Piotr", "Piotr", "note to self");
+        assert_eq!(socket_1.read(), dm);
+        socket_1.send_line("/nick");
+        socket_1.read(); // skip the rename
+
+        socket_1.send_line("/msg nobody hello");
+        assert_eq!(socket_1.read(), error_msg(T0, "nobody is not online"));
+        socket_1.send_line("/msg kasia");
+        assert_eq!(
+            socket_1.read(),
+            error_msg(T0, "Usage: /msg [user] [message]")
+        );
+
+        // once kasia is gone so is her session
+        socket_2.send_line("/part");
+        socket_2.read(); // skip room msg
+        assert_eq!(socket_1.read(), join_msg(T0, "kasia", "lobby"));
+        drop(socket_2);
+        assert_eq!(socket_1.read(), leave_msg(T0, "kasia", "lobby"));
+        socket_1.send_line("/msg kasia still there?");
+        assert_eq!(socket_1.read(), error_msg(T0, "kasia is not online"));
+    }
+
+    fn roster(users: &[&str]) -> Message {
+        Message::Roster {
+            users: users.iter().map(|u| u.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn presence() {
+        let server = TestServer::start();
+        let mut socket_1 = server.login("piotrek");
+        let mut socket_2 = server.login("kasia");
+        socket_1.send_line("/join elsewhere");
+        socket_1.read(); // skip room msg
+        assert_eq!(socket_2.read(), leave_msg(T0, "piotrek", "lobby"));
+
+        // the roster covers every room
+        socket_2.send_line("/who");
+        assert_eq!(
+            socket_2.read(),
+            system_msg(T0, "Online (2): kasia, piotrek")
+        );
+
+        // live updates on login and logout
+        let socket_3 = server.login("ptr");
+        let mut seen = socket_1.read_any();
+        while seen != roster(&["kasia", "piotrek", "ptr"]) {
+            seen = socket_1.read_any();
+        }
+        drop(socket_3);
+        while seen != roster(&["kasia", "piotrek"]) {
+            seen = socket_1.read_any();
+        }
+        // and on nickname changes, by the new name
+        socket_1.send_line("/nick Piotr");
+        while seen != roster(&["Piotr", "kasia"]) {
+            seen = socket_2.read_any();
+        }
+    }
+
+    #[test]
+    fn slash_commands() {
+        let server = TestServer::start();
+        let mut socket_1 = server.login("piotrek");
+        let mut socket_2 = server.login("kasia");
+        socket_1.read(); // skip kasia's join
+
+        socket_1.send_line("/help");
+        assert_eq!(
+            socket_1.read(),
+            system_msg(T0, "Commands (start a message with // to send a /):")
+        );
+        assert_eq!(
+            socket_1.read(),
+            system_msg(T0, "/help [command] - Lists the commands, or explains one")
+        );
+        let mut seen = socket_1.read();
+        while seen != system_msg(T0, "/who - Lists everyone online") {
+            // admin commands are left out for everyone else
+            assert!(!format!("{seen:?}").contains("/announce"));
+            seen = socket_1.read();
+        }
+        socket_1.send_line("/help join");
+        assert_eq!(
+            socket_1.read(),
+            system_msg(
+                T0,
+                "/join #room - Moves you to a room, creating it if needed"
+            )
+        );
+
+        socket_1.send_line("/dance");
+        assert_eq!(
+            socket_1.read(),
+            error_msg(T0, "Unknown command /dance, see /help")
+        );
+        socket_1.send_line("/announce hello");
+        assert_eq!(
+            socket_1.read(),
+            error_msg(T0, "Only admins can use /announce")
+        );
+        socket_1.send_line("//dance is not a command");
+        let msg = normal_msg(T0, "piotrek", "/dance is not a command");
+        assert_eq!(socket_1.read(), msg);
+        assert_eq!(socket_2.read(), msg);
+
+        socket_1.send_line("/me waves");
+        assert_eq!(socket_2.read(), action_msg(T0, "piotrek", "waves"));
+        socket_1.send_line("/me");
+        assert_eq!(socket_1.read(), action_msg(T0, "piotrek", "waves"));
+        assert_eq!(socket_1.read(), error_msg(T0, "Usage: /me [action]"));
+
+        socket_1.send_line("/nick Piotr");
+        let renamed = system_msg(T0, "piotrek is now known as Piotr");
+        assert_eq!(socket_1.read(), renamed);
+        assert_eq!(socket_2.read(), renamed);
+        socket_1.send_line("hi");
+        assert_eq!(socket_2.read(), normal_msg(T0, "Piotr", "hi"));
+        socket_2.send_line("/who");
+        assert_eq!(
+            socket_2.read(),
+            system_msg(T0, "Online (2): kasia, Piotr (piotrek)")
+        );
+        socket_2.send_line("/nick Piotr");
+        assert_eq!(
+            socket_2.read(),
+            error_msg(T0, "Somebody online goes by Piotr")
+        );
+        socket_2.send_line("/nick piotrek");
+        assert_eq!(
+            socket_2.read(),
+            error_msg(T0, "piotrek is somebody's username")
+        );
+        socket_2.send_line("/nick no spaces");
+        assert!(matches!(socket_2.read(), Message::Error { .. }));
+        socket_1.send_line("/nick");
+        assert_eq!(socket_1.read(), normal_msg(T0, "Piotr", "hi"));
+        let back = system_msg(T0, "Piotr is now known as piotrek");
+        assert_eq!(socket_1.read(), back);
+        assert_eq!(socket_2.read(), back);
+
+        socket_2.send_line("/quit");
+        assert_eq!(socket_2.read(), bye_msg(T0, "Bye!"));
+        assert!(socket_2.closed());
+        assert_eq!(socket_1.read(), leave_msg(T0, "kasia", "lobby"));
+    }
+
+    #[test]
+    fn admin_commands() {
+        let server = TestServer::with_config(Config {
+            admins: vec!["piotrek".to_string()],
+            ..Config::default()
+        });
+        let mut admin = server.login("piotrek");
+        let mut user = server.login("kasia");
+
+        // wherever they are, everyone hears it
+        user.send_line("/join elsewhere");
+        assert_eq!(user.read(), room_msg(T0, "elsewhere"));
+        assert_eq!(user.read(), join_msg(T0, "kasia", "elsewhere"));
+        admin.send_line("/announce Restart at noon");
+        let announcement = system_msg(T0, "Announcement from piotrek: Restart at noon");
+        for client in [&mut user, &mut admin] {
+            let mut seen = client.read();
+            while seen != announcement {
+                assert!(!matches!(seen, Message::Error { .. }));
+                seen = client.read();
+            }
+        }
+        admin.send_line("/help announce");
+        let help = system_msg(
+            T0,
+            "/announce [message] - Tells everyone online, whatever room they're in",
+        );
+        let mut seen = admin.read();
+        while matches!(seen, Message::Leave { .. }) {
+            seen = admin.read();
+        }
+        assert_eq!(seen, help);
+    }
+
+    #[test]
+    fn duplicate_login_rejected() {
+        let server = TestServer::start();
+        let mut socket_1 = server.login("piotrek");
+        let mut socket_2 = server.connect();
+        socket_2.read(); // skip login prompt
+        socket_2.send_line("piotrek:123456");
+        assert_eq!(socket_2.read(), error_msg(T0, ALREADY_LOGGED_IN_MSG));
+        let addr = socket_2.local_addr();
+        assert_eq!(
+            socket_1.read(),
+            system_msg(T0, &format!("Someone tried to log in as you from {addr}"))
+        );
+
+        // the first session is untouched and still the only one
+        socket_1.send_line("/who");
+        assert_eq!(socket_1.read(), system_msg(T0, "Online (1): piotrek"));
+
+        // a wrong password can't be used to kick anybody
+        socket_2.send_line("kick piotrek:wrong");
+        assert_eq!(socket_2.read(), error_msg(T0, BAD_LOGIN_MSG));
+    }
+
+    #[test]
+    fn kick_older_session() {
+        let server = TestServer::start();
+        let mut socket_1 = server.login("piotrek");
+        let mut socket_2 = server.connect();
+        socket_2.read(); // skip login prompt
+        socket_2.send_line("kick piotrek:123456");
+        assert_eq!(socket_2.read(), system_msg(T0, WELCOME_MSG));
+        assert_eq!(socket_2.read(), room_msg(T0, "lobby"));
+        socket_2.read_history();
+
+        // the old session may still see the new one join before it's kicked
+        let mut seen = socket_1.read();
+        while seen == join_msg(T0, "piotrek", "lobby") {
+            seen = socket_1.read();
+        }
+        assert_eq!(seen, bye_msg(T0, KICKED_MSG));
+        assert!(socket_1.closed());
+
+        // the old connection is closed only after it cleaned up after itself
+        socket_2.send_line("/who");
+        let mut seen = socket_2.read();
+        while !matches!(seen, Message::System { .. }) {
+            seen = socket_2.read();
+        }
+        assert_eq!(seen, system_msg(T0, "Online (1): piotrek"));
+    }
+
+    #[test]
+    fn room_names() {
+        assert_eq!(rooms::parse_room_name("#rust"), Some("rust"));
+        assert_eq!(rooms::parse_room_name("rust-pl_2"), Some("rust-pl_2"));
+        assert_eq!(rooms::parse_room_name("#"), None);
+        assert_eq!(rooms::parse_room_name(""), None);
+        assert_eq!(rooms::parse_room_name("two words"), None);
+        assert_eq!(rooms::parse_room_name(&"a".repeat(21)), None);
+    }
+
+    #[test]
+    fn history_replayed_on_login() {
+        let server = TestServer::start();
+        let mut socket = server.login("piotrek");
+        socket.send_line("remember me");
+        assert_eq!(socket.read(), normal_msg(T0, "piotrek", "remember me"));
+
+        let mut late = server.connect();
+        late.read(); // skip login prompt
+        late.send_line("kasia:123456");
+        assert_eq!(late.read(), system_msg(T0, WELCOME_MSG));
+        assert_eq!(late.read(), room_msg(T0, "lobby"));
+        assert_eq!(
+            late.read_history(),
+            [
+                join_msg(T0, "piotrek", "lobby"),
+                normal_msg(T0, "piotrek", "remember me")
+            ]
+        );
+        // joining is live, not part of the replay
+        assert_eq!(late.read(), join_msg(T0, "kasia", "lobby"));
+        assert_eq!(socket.read(), join_msg(T0, "kasia", "lobby"));
+
+        drop(late);
+        assert_eq!(socket.read(), leave_msg(T0, "kasia", "lobby"));
+    }
+
+    #[test]
+    fn resume_replays_what_was_missed() {
+        let server = TestServer::start();
+        let mut socket_1 = server.login("piotrek");
+        let mut socket_2 = server.login("kasia");
+        socket_1.read(); // skip kasia's join
+        socket_1.send_line("/join away");
+        assert_eq!(socket_1.read(), room_msg(T0, "away"));
+        assert_eq!(socket_1.read(), join_msg(T0, "piotrek", "away"));
+        assert_eq!(socket_2.read(), leave_msg(T0, "piotrek", "lobby"));
+        socket_2.send_line("/join away");
+        assert_eq!(socket_2.read(), room_msg(T0, "away"));
+        assert_eq!(socket_2.read(), join_msg(T0, "kasia", "away"));
+        assert_eq!(socket_1.read(), join_msg(T0, "kasia", "away"));
+
+        drop(socket_1);
+        assert_eq!(socket_2.read(), leave_msg(T0, "piotrek", "away"));
+        socket_2.send_line("while you were away");
+        assert_eq!(
+            socket_2.read(),
+            normal_msg(T0, "kasia", "while you were away")
+        );
+
+        let mut socket_1 = server.connect();
+        socket_1.read(); // skip login prompt
+        socket_1.send_line("resume piotrek:123456");
+        assert_eq!(socket_1.read(), system_msg(T0, WELCOME_MSG));
+        assert_eq!(socket_1.read(), room_msg(T0, "away"));
+        assert_eq!(
+            socket_1.read(),
+            Message::History {
+                room: "away".to_string(),
+                messages: vec![normal_msg(T0, "kasia", "while you were away")],
+            }
+        );
+        assert_eq!(socket_1.read(), join_msg(T0, "piotrek", "away"));
+        assert_eq!(socket_2.read(), join_msg(T0, "piotrek", "away"));
+
+        // resuming without having left anywhere is a plain login
+        let mut socket_3 = server.connect();
+        socket_3.read(); // skip login prompt
+        socket_3.send_line("resume ptr:123456");
+        assert_eq!(socket_3.read(), system_msg(T0, WELCOME_MSG));
+        assert_eq!(socket_3.read(), room_msg(T0, "lobby"));
+    }
+
+    #[test]
+    fn backfill_turned_off() {
+        let mut config = Config::default();
+        config.limits.backfill = 0;
+        let server = TestServer::with_config(config);
+        let mut socket_1 = server.login("piotrek");
+        let mut socket_2 = server.login("kasia");
+        socket_1.read(); // skip kasia's join
+        drop(socket_1);
+        assert_eq!(socket_2.read(), leave_msg(T0, "piotrek", "lobby"));
+        socket_2.send_line("while you were away");
+        socket_2.read();
+
+        let mut socket_1 = server.connect();
+        socket_1.read(); // skip login prompt
+        socket_1.send_line("resume piotrek:123456");
+        assert_eq!(socket_1.read(), system_msg(T0, WELCOME_MSG));
+        assert_eq!(socket_1.read(), room_msg(T0, "lobby"));
+        assert_eq!(socket_1.read_history(), []);
+        assert_eq!(socket_1.read(), join_msg(T0, "piotrek", "lobby"));
+    }
+
+    #[test]
+    fn history_survives_restart() {
+        let path = temp_db("history");
+        let (tx, mut rx) = tokio::sync::broadcast::channel(16);
+        let history = History::open(&path).unwrap();
+        for i in 0..3 {
+            history.record(&tx, "lobby", normal_msg(T0, "piotrek", &i.to_string()));
+        }
+        history.record(&tx, "rust", normal_msg(T0, "kasia", "hi"));
+        assert_eq!(rx.try_recv().unwrap().id, 0);
+        drop(history);
+
+        let history = History::open(&path).unwrap();
+        let last = history.last("lobby", 2);
+        assert_eq!(last.len(), 2);
+        assert_eq!(last[0].msg, normal_msg(T0, "piotrek", "1"));
+        assert_eq!(last[1].msg, normal_msg(T0, "piotrek", "2"));
+        assert_eq!(history.last("rust", 20).len(), 1);
+        assert!(history.last("nowhere", 20).is_empty());
+        let since: Vec<u64> = history
+            .since("lobby", Some(0))
+            .iter()
+            .map(|e| e.id)
+            .collect();
+        assert_eq!(since, [1, 2]);
+        assert_eq!(history.since("lobby", None).len(), 3);
+        assert!(history.since("lobby", Some(3)).is_empty());
+        assert_eq!(history.last_leave("piotrek"), None);
+
+        // ids carry on where the previous run stopped
+        history.record(&tx, "lobby", normal_msg(T0, "piotrek", "again"));
+        assert_eq!(history.last("lobby", 1)[0].id, 4);
+        history.record(&tx, "rust", leave_msg(T0, "piotrek", "rust"));
+        history.record(&tx, "lobby", leave_msg(T0, "kasia", "lobby"));
+        assert_eq!(history.last_leave("piotrek"), Some(("rust".to_string(), 5)));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn history_rejects_corrupt_file() {
+        let path = temp_db("bad_history");
+        std::fs::write(&path, r#"{"id":0,"room":"lobby","msg":{"type":"chat","time":"noon","from":"ptr","text":"hi"}}"#).unwrap();
+        assert!(History::open(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn replay_fits_in_a_frame() {
+        let text = "a".repeat(config::DEFAULT_MAX_LINE_LENGTH);
+        let messages: Vec<Message> = (0..history::KEEP_PER_ROOM)
+            .map(|_| normal_msg(T0, "piotrek", &text))
+            .collect();
+        let replay = history_msg("lobby", messages.clone());
+        assert!(replay.encode().len() <= chat_proto::MAX_FRAME_LENGTH);
+        let Message::History { messages: kept, .. } = replay else {
+            unreachable!()
+        };
+        // the oldest go, the newest stay
+        assert!(!kept.is_empty() && kept.len() < messages.len());
+        assert_eq!(kept[..], messages[messages.len() - kept.len()..]);
+
+        let short = history_msg("lobby", messages[..3].to_vec());
+        assert_eq!(
+            short,
+            Message::History {
+                room: "lobby".to_string(),
+                messages: messages[..3].to_vec(),
+            }
+        );
+    }
+
+    #[test]
+    fn lagging_too_often_disconnects() {
+        let start = std::time::Instant::now();
+        let mut lags = rooms::Lags::default();
+        for i in 1..rooms::MAX_LAGS as u64 {
+            assert!(!lags.lagged(start + std::time::Duration::from_secs(i)));
+        }
+        assert!(lags.lagged(start + std::time::Duration::from_secs(10)));
+
+        // old lags are forgiven
+        let mut lags = rooms::Lags::default();
+        let later = start + rooms::LAG_WINDOW + std::time::Duration::from_secs(1);
+        for _ in 1..rooms::MAX_LAGS {
+            assert!(!lags.lagged(start));
+        }
+        assert!(!lags.lagged(later));
+    }
+
+    /// A server state of its own, knowing piotrek and kasia (password 123456) and nothing else.
+    fn private_state(name: &str, config: Config) -> Arc<State> {
+        let users = UserDb::load(temp_db(&format!("{name}_users"))).unwrap();
+        for uname in ["piotrek", "kasia"] {
+            users.add_user(uname, "123456").unwrap();
+        }
+        let history = History::open(temp_db(&format!("{name}_history"))).unwrap();
+        Arc::new(State::new(config, users, history, FixedClock(T0)))
+    }
+
+    #[test]
+    fn builtin_commands_are_the_shared_ones() {
+        let builtin: Vec<_> = commands::Commands::builtin()
+            .available(commands::Permission::Admin)
+            .map(|c| *c.info())
+            .collect();
+        assert_eq!(builtin, chat_proto::COMMANDS);
+    }
+
+    #[tokio::test]
+    async fn graceful_shutdown() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let state = private_state("shutdown", Config::default());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve(listener, state.clone(), None));
+
+        let (reader, mut writer) = tokio::net::TcpStream::connect(addr)
+            .await
+            .unwrap()
+            .into_split();
+        let mut chatting = BufReader::new(reader).lines();
+        writer.write_all(b"piotrek:123456\n").await.unwrap();
+        let mut seen = Message::decode(&chatting.next_line().await.unwrap().unwrap()).unwrap();
+        while seen != join_msg(T0, "piotrek", "lobby") {
+            seen = Message::decode(&chatting.next_line().await.unwrap().unwrap()).unwrap();
+        }
+        // still at the login prompt
+        let mut logging_in =
+            BufReader::new(tokio::net::TcpStream::connect(addr).await.unwrap()).lines();
+        let line = logging_in.next_line().await.unwrap().unwrap();
+        assert_eq!(
+            Message::decode(&line).unwrap(),
+            system_msg(T0, LOGIN_PROMPT)
+        );
+
+        state.shutdown.send_replace(true);
+        let mut seen = Message::decode(&chatting.next_line().await.unwrap().unwrap()).unwrap();
+        while matches!(seen, Message::Roster { .. }) {
+            seen = Message::decode(&chatting.next_line().await.unwrap().unwrap()).unwrap();
+        }
+        assert_eq!(seen, system_msg(T0, SHUTDOWN_MSG));
+        assert_eq!(chatting.next_line().await.unwrap(), None);
+        let line = logging_in.next_line().await.unwrap().unwrap();
+        assert_eq!(
+            Message::decode(&line).unwrap(),
+            system_msg(T0, SHUTDOWN_MSG)
+        );
+        assert_eq!(logging_in.next_line().await.unwrap(), None);
+
+        // everyone left, so the server is done well before the timeout and takes nobody new
+        tokio::time::timeout(SHUTDOWN_TIMEOUT, server)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(tokio::net::TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn tls_login() {
+        use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+        use tokio_rustls::rustls::{self, pki_types::ServerName};
+
+        let rcgen::CertifiedKey { cert, key_pair } =
+            rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let (cert_path, key_path) = (temp_db("tls_cert"), temp_db("tls_key"));
+        std::fs::write(&cert_path, cert.pem()).unwrap();
+        std::fs::write(&key_path, key_pair.serialize_pem()).unwrap();
+        let acceptor = tls::acceptor(&cert_path, &key_path).unwrap();
+
+        let config = Config {
+            motd: Some("Traffic is encrypted".to_string()),
+            ..Config::default()
+        };
+        let state = private_state("tls", config);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, state, Some(acceptor)));
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert.der().clone()).unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let socket = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let socket = tokio_rustls::TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost").unwrap(), socket)
+            .await
+            .unwrap();
+        let (reader, mut writer) = tokio::io::split(socket);
+        let mut lines = BufReader::new(reader).lines();
+        let line = lines.next_line().await.unwrap().unwrap();
+        assert_eq!(
+            Message::decode(&line).unwrap(),
+            system_msg(T0, LOGIN_PROMPT)
+        );
+        writer.write_all(b"piotrek:123456\n").await.unwrap();
+        let line = lines.next_line().await.unwrap().unwrap();
+        assert_eq!(Message::decode(&line).unwrap(), system_msg(T0, WELCOME_MSG));
+        let line = lines.next_line().await.unwrap().unwrap();
+        let motd = system_msg(T0, "Traffic is encrypted");
+        assert_eq!(Message::decode(&line).unwrap(), motd);
+
+        // plain text never gets as far as the login prompt
+        let mut plain = tokio::net::TcpStream::connect(addr).await.unwrap();
+        plain.write_all(b"piotrek:123456\n").await.unwrap();
+        let mut buf = vec![];
+        let _ = plain.read_to_end(&mut buf).await;
+        assert!(!String::from_utf8_lossy(&buf).contains(LOGIN_PROMPT));
+
+        for path in [cert_path, key_path] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn config_file_with_cli_overrides() {
+        let path = temp_db("config");
+        std::fs::write(
+            &path,
+            r#"
+                bind = ["127.0.0.1"]
+                port = 9000
+                motd = "Be nice"
+
+                [limits]
+                max_line_length = 2048
+
+                [log]
+                level = "debug"
+            "#,
+        )
+        .unwrap();
+        let from_file = Config::from_file(&path).unwrap();
+        assert_eq!(from_file.port, 9000);
+        assert_eq!(from_file.limits.max_line_length, 2048);
+        // whatever the file leaves out keeps its default
+        assert_eq!(from_file.limits.room_capacity, rooms::DEFAULT_ROOM_CAPACITY);
+        assert_eq!(from_file.log.level, log::LevelFilter::Debug);
+
+        let cli = Cli::try_parse_from([
+            "chat_server",
+            "--config",
+            path.to_str().unwrap(),
+            "--bind",
+            "127.0.0.1",
+            "--bind",
+            "::1",
+            "--port",
+            "9001",
+            "--replay-length",
+            "5",
+            "--backfill",
+            "0",
+            "--admin",
+            "piotrek",
+        ])
+        .unwrap();
+        let config = Config::load(&cli).unwrap();
+        assert_eq!(
+            config.bind,
+            ["127.0.0.1", "::1"].map(|ip| ip.parse::<std::net::IpAddr>().unwrap())
+        );
+        assert_eq!(config.port, 9001);
+        assert_eq!(config.limits.replay_length, 5);
+        assert_eq!(config.limits.backfill, 0);
+        assert_eq!(config.limits.max_line_length, 2048);
+        assert_eq!(config.motd.as_deref(), Some("Be nice"));
+        assert_eq!(config.admins, ["piotrek"]);
+        std::fs::remove_file(&path).unwrap();
+
+        let cli = Cli::try_parse_from(["chat_server", "adduser", "ola", "secret"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Adduser { .. })));
+    }
+
+    #[test]
+    fn example_config_is_the_default() {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("config.example.toml");
+        assert_eq!(Config::from_file(&path).unwrap(), Config::default());
+    }
+
+    #[test]
+    fn config_rejects_mistakes() {
+        let path = temp_db("bad_config");
+        std::fs::write(&path, "prot = 9000\n").unwrap();
+        assert!(Config::from_file(&path).is_err());
+        std::fs::write(&path, "[limits]\nroom_capacity = -1\n").unwrap();
+        assert!(Config::from_file(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+
+        let invalid = |args: &[&str]| {
+            let cli = Cli::try_parse_from([&["chat_server"], args].concat()).unwrap();
+            Config::load(&cli).is_err()
+        };
+        assert!(invalid(&["--max-line-length", "10"]));
+        assert!(invalid(&["--room-capacity", "0"]));
+        assert!(invalid(&["--replay-length", "100000"]));
+        // a full replay has to fit in one frame
+        assert!(invalid(&["--replay-length", "500"]));
+        assert!(!invalid(&[
+            "--replay-length",
+            "500",
+            "--max-line-length",
+            "256"
+        ]));
+        // and so does a single message, replay or not
+        assert!(invalid(&[
+            "--replay-length",
+            "0",
+            "--max-line-length",
+            "1000000"
+        ]));
+        assert!(invalid(&["--tls-cert", "cert.pem"]));
+        assert!(invalid(&["--motd", "two\nlines"]));
+        assert!(!invalid(&["--motd", "one line"]));
+        assert!(Cli::try_parse_from(["chat_server", "--bind", "localhost"]).is_err());
+    }
+
+    fn temp_db(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("chat_{name}_{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn user_db_registered_vs_unknown() {
+        let path = temp_db("users");
+        let db = UserDb::load(&path).unwrap();
+        db.add_user("piotrek", "123456").unwrap();
+        assert_eq!(
+            db.add_user("piotrek", "other1"),
+            Err(users::LoginError::UserExists)
+        );
+        assert_eq!(
+            db.add_user("aaaaaaaaaaaaaaaaaaaaaaaaaaa", "123456"),
+            Err(users::LoginError::InvalidUsername)
+        );
+        assert_eq!(
+            db.register("kasia:12345"),
+            Err(users::LoginError::PasswordTooShort)
+        );
+        assert_eq!(db.register("kasia:123456"), Ok("kasia".to_string()));
+
+        assert_eq!(db.try_to_login("piotrek:123456"), Ok("piotrek".to_string()));
+        assert_eq!(
+            db.try_to_login("piotrek:654321"),
+            Err(users::LoginError::WrongPassword)
+        );
+        assert_eq!(
+            db.try_to_login("ola:123456"),
+            Err(users::LoginError::UnknownUser)
+        );
+        assert_eq!(db.try_to_login("kasia:123456"), Ok("kasia".to_string()));
+        assert_eq!(
+            db.try_to_login("piotrek:123:456"),
+            Err(users::LoginError::Malformed)
+        );
+        assert_eq!(
+            db.try_to_login("piotrek"),
+            Err(users::LoginError::Malformed)
+        );
+
+        // the hash is salted and never stores the password itself
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(content.starts_with("piotrek:$argon2"));
+        assert!(!content.contains("123456"));
+
+        // accounts survive a reload
+        let db = UserDb::load(&path).unwrap();
+        assert_eq!(db.len(), 2);
+        assert_eq!(db.try_to_login("piotrek:123456"), Ok("piotrek".to_string()));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn user_db_rejects_corrupt_file() {
+        let path = temp_db("corrupt");
+        std::fs::write(&path, "piotrek:not-a-hash\n").unwrap();
+        assert!(UserDb::load(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
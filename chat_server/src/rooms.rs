@@ -0,0 +1,95 @@
+use crate::history::Entry;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::broadcast::{self, Receiver, Sender};
+
+/// Everyone lands here after logging in, it's never removed.
+pub const DEFAULT_ROOM: &str = "lobby";
+pub const MAX_ROOM_NAME_LENGTH: usize = 20;
+/// Messages a room buffers for each member before the slowest one starts missing them.
+pub const DEFAULT_ROOM_CAPACITY: usize = 16;
+/// Falling behind this many times within `LAG_WINDOW` gets a client disconnected.
+pub const MAX_LAGS: usize = 3;
+pub const LAG_WINDOW: Duration = Duration::from_secs(60);
+
+/// Accepts `name` or `#name`, returns the name without the `#`.
+pub fn parse_room_name(arg: &str) -> Option<&str> {
+    let name = arg.strip_prefix('#').unwrap_or(arg);
+    let valid_char = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+    if name.is_empty() || name.len() > MAX_ROOM_NAME_LENGTH || !name.chars().all(valid_char) {
+        return None;
+    }
+    Some(name)
+}
+
+/// Named chat rooms, each with its own broadcast channel.
+///
+/// A room exists as long as somebody is subscribed to it.
+pub struct Rooms {
+    capacity: usize,
+    rooms: Mutex<HashMap<String, Sender<Arc<Entry>>>>,
+}
+
+impl Rooms {
+    pub fn new(capacity: usize) -> Self {
+        let (tx, _) = broadcast::channel(capacity);
+        Self {
+            capacity,
+            rooms: Mutex::new(HashMap::from([(DEFAULT_ROOM.to_string(), tx)])),
+        }
+    }
+
+    /// Subscribes to `name`, creating the room if needed.
+    pub fn join(&self, name: &str) -> (Sender<Arc<Entry>>, Receiver<Arc<Entry>>) {
+        let mut rooms = self.rooms.lock().unwrap();
+        let tx = rooms
+            .entry(name.to_string())
+            .or_insert_with(|| broadcast::channel(self.capacity).0);
+        (tx.clone(), tx.subscribe())
+    }
+
+    /// Drops `name` if its last member is gone. Call after dropping the receiver.
+    pub fn cleanup(&self, name: &str) {
+        let mut rooms = self.rooms.lock().unwrap();
+        if name != DEFAULT_ROOM && rooms.get(name).is_some_and(|tx| tx.receiver_count() == 0) {
+            rooms.remove(name);
+        }
+    }
+
+    /// Room names with their member counts, sorted by name.
+    pub fn list(&self) -> Vec<(String, usize)> {
+        let mut list: Vec<_> = self
+            .rooms
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, tx)| (name.clone(), tx.receiver_count()))
+            .collect();
+        list.sort();
+        list
+    }
+}
+
+/// Remembers when a client fell behind its room, to spot the ones that can't keep up at all.
+#[derive(Default)]
+pub struct Lags {
+    times: VecDeque<Instant>,
+}
+
+impl Lags {
+    /// Records falling behind at `now`, returns true once it happened too often.
+    pub fn lagged(&mut self, now: Instant) -> bool {
+        while self
+            .times
+            .front()
+            .is_some_and(|t| now.duration_since(*t) > LAG_WINDOW)
+        {
+            self.times.pop_front();
+        }
+        self.times.push_back(now);
+        self.times.len() >= MAX_LAGS
+    }
+}
@@ -0,0 +1,154 @@
+use chat_proto::Message;
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    sync::{Arc, Mutex},
+};
+use tokio::sync::{
+    mpsc::{self, UnboundedReceiver, UnboundedSender},
+    Notify,
+};
+
+/// A logged in connection: a way to reach it and a way to end it.
+#[derive(Clone)]
+pub struct Session {
+    tx: UnboundedSender<Message>,
+    kick: Arc<Notify>,
+    /// What the user goes by in the chat, if not their username.
+    nick: Option<String>,
+}
+
+impl Session {
+    pub fn new() -> (Self, UnboundedReceiver<Message>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let session = Self {
+            tx,
+            kick: Arc::new(Notify::new()),
+            nick: None,
+        };
+        (session, rx)
+    }
+
+    /// Resolves once another connection took this session over.
+    pub async fn kicked(&self) {
+        self.kick.notified().await
+    }
+}
+
+/// Logged in users, at most one session per name.
+#[derive(Default)]
+pub struct Sessions {
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+impl Sessions {
+    /// Registers `session` as `uname`, returns false if they're already logged in.
+    pub fn claim(&self, uname: &str, session: Session) -> bool {
+        match self.sessions.lock().unwrap().entry(uname.to_string()) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(e) => {
+                e.insert(session);
+                true
+            }
+        }
+    }
+
+    /// Registers `session` as `uname`, kicking out whoever was logged in before.
+    pub fn take_over(&self, uname: &str, session: Session) {
+        let old = self
+            .sessions
+            .lock()
+            .unwrap()
+            .insert(uname.to_string(), session);
+        if let Some(old) = old {
+            old.kick.notify_one();
+        }
+    }
+
+    /// Forgets `uname`, unless the entry already belongs to a newer connection.
+    pub fn remove(&self, uname: &str, session: &Session) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions
+            .get(uname)
+            .is_some_and(|s| s.tx.same_channel(&session.tx))
+        {
+            sessions.remove(uname);
+        }
+    }
+
+    /// Everyone logged in with the nickname they picked (if any), sorted by username.
+    pub fn who(&self) -> Vec<(String, Option<String>)> {
+        let sessions = self.sessions.lock().unwrap();
+        let mut who: Vec<_> = sessions
+            .iter()
+            .map(|(uname, s)| (uname.clone(), s.nick.clone()))
+            .collect();
+        who.sort();
+        who
+    }
+
+    /// The username of whoever online goes by `name`, as their username or nickname, and
+    /// the name they're shown by.
+    pub fn find(&self, name: &str) -> Option<(String, String)> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions
+            .iter()
+            .map(|(uname, s)| (uname, s.nick.as_ref().unwrap_or(uname)))
+            .find(|(uname, shown)| *uname == name || *shown == name)
+            .map(|(uname, shown)| (uname.clone(), shown.clone()))
+    }
+
+    /// Sets or clears `uname`'s nickname, returns false if somebody else online goes by it.
+    pub fn set_nick(&self, uname: &str, nick: Option<&str>) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        let taken = |n: &str| {
+            sessions
+                .iter()
+                .any(|(other, s)| other != uname && (other == n || s.nick.as_deref() == Some(n)))
+        };
+        if nick.is_some_and(taken) {
+            return false;
+        }
+        match sessions.get_mut(uname) {
+            Some(s) => {
+                s.nick = nick.map(str::to_string);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Delivers `msg` to everyone logged in.
+    pub fn broadcast(&self, msg: &Message) {
+        for s in self.sessions.lock().unwrap().values() {
+            let _ = s.tx.send(msg.clone());
+        }
+    }
+
+    /// Pushes the current roster, by the names people go by, to every session.
+    pub fn broadcast_roster(&self) {
+        let sessions = self.sessions.lock().unwrap();
+        let users = sorted_names(&sessions);
+        for s in sessions.values() {
+            let _ = s.tx.send(Message::Roster {
+                users: users.clone(),
+            });
+        }
+    }
+
+    /// Delivers `msg` to `uname` only, returns false if they aren't online.
+    pub fn send_to(&self, uname: &str, msg: Message) -> bool {
+        match self.sessions.lock().unwrap().get(uname) {
+            Some(s) => s.tx.send(msg).is_ok(),
+            None => false,
+        }
+    }
+}
+
+fn sorted_names(sessions: &HashMap<String, Session>) -> Vec<String> {
+    let mut users: Vec<String> = sessions
+        .iter()
+        .map(|(uname, s)| s.nick.clone().unwrap_or_else(|| uname.clone()))
+        .collect();
+    users.sort();
+    users
+}
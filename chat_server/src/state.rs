@@ -0,0 +1,51 @@
+use crate::{
+    clock::Clock, commands::Commands, config::Config, history::History, rooms::Rooms,
+    sessions::Sessions, users::UserDb,
+};
+use chat_proto::Timestamp;
+use tokio::sync::watch;
+
+/// Everything the connection handlers share.
+pub struct State {
+    pub config: Config,
+    pub users: UserDb,
+    pub history: History,
+    pub rooms: Rooms,
+    pub sessions: Sessions,
+    pub commands: Commands,
+    pub clock: Box<dyn Clock>,
+    /// Flips to true once the server is going down.
+    pub shutdown: watch::Sender<bool>,
+}
+
+impl State {
+    pub fn new(
+        config: Config,
+        users: UserDb,
+        history: History,
+        clock: impl Clock + 'static,
+    ) -> Self {
+        Self {
+            rooms: Rooms::new(config.limits.room_capacity),
+            config,
+            users,
+            history,
+            sessions: Sessions::default(),
+            commands: Commands::builtin(),
+            clock: Box::new(clock),
+            shutdown: watch::channel(false).0,
+        }
+    }
+
+    /// What time to put on a message made now.
+    pub fn now(&self) -> Timestamp {
+        self.clock.now()
+    }
+
+    /// Resolves once the server starts shutting down, right away if it already did.
+    pub async fn shutting_down(&self) {
+        let mut shutdown = self.shutdown.subscribe();
+        // the sender lives in `self`, so this can't fail
+        let _ = shutdown.wait_for(|&down| down).await;
+    }
+}
@@ -0,0 +1,182 @@
+//! A server the tests start for themselves, and a client to script against it.
+//!
+//! Every `TestServer` listens on a port of its own with users and history of its own, so
+//! tests don't need a server running beforehand and don't get in each other's way.
+
+use crate::clock::FixedClock;
+use crate::config::Config;
+use crate::handler::{join_msg, room_msg, system_msg, WELCOME_MSG};
+use crate::history::History;
+use crate::rooms::DEFAULT_ROOM;
+use crate::state::State;
+use crate::users::UserDb;
+use chat_proto::{Message, Timestamp};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// What the test servers' clock says, 2024-03-10 12:00 UTC.
+pub const T0: Timestamp = match Timestamp::from_timestamp(1_710_072_000, 0) {
+    Some(t0) => t0,
+    None => panic!("T0 is in range"),
+};
+
+/// The password of every fixture user.
+pub const PASSWORD: &str = "123456";
+
+/// How long a `TestClient` waits for a message before failing the test.
+pub const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// piotrek, kasia and ptr.
+const FIXTURE_USERS: &[u8] = include_bytes!("../fixtures/users.db");
+
+/// A server on 127.0.0.1 and a port the system picked, with the fixture users, an empty
+/// history and the clock stopped at `T0`; it shuts down when dropped.
+pub struct TestServer {
+    pub addr: SocketAddr,
+    pub state: Arc<State>,
+    thread: Option<JoinHandle<()>>,
+    files: Vec<PathBuf>,
+}
+
+impl TestServer {
+    pub fn start() -> Self {
+        Self::with_config(Config::default())
+    }
+
+    pub fn with_config(config: Config) -> Self {
+        let users_db = temp_file("users");
+        std::fs::write(&users_db, FIXTURE_USERS).unwrap();
+        let history_log = temp_file("history");
+        let users = UserDb::load(&users_db).unwrap();
+        let history = History::open(&history_log).unwrap();
+        let state = Arc::new(State::new(config, users, history, FixedClock(T0)));
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        // a runtime of its own, so sync and async tests alike can talk to it
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let serve_state = state.clone();
+        let thread = std::thread::spawn(move || {
+            runtime.block_on(async move {
+                let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                crate::serve(listener, serve_state, None).await;
+            });
+        });
+        Self {
+            addr,
+            state,
+            thread: Some(thread),
+            files: vec![users_db, history_log],
+        }
+    }
+
+    /// A client at the login prompt, which is still unread.
+    pub fn connect(&self) -> TestClient {
+        let socket = TcpStream::connect(self.addr).unwrap();
+        socket.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+        TestClient { socket }
+    }
+
+    /// A client logged in as `uname`, with everything up to its own join read.
+    pub fn login(&self, uname: &str) -> TestClient {
+        let mut client = self.connect();
+        client.read(); // skip login prompt
+        client.send_line(&format!("{uname}:{PASSWORD}"));
+        assert_eq!(client.read(), system_msg(T0, WELCOME_MSG));
+        assert_eq!(client.read(), room_msg(T0, DEFAULT_ROOM));
+        client.read_history();
+        assert_eq!(client.read(), join_msg(T0, uname, DEFAULT_ROOM));
+        client
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.state.shutdown.send_replace(true);
+        if let Some(thread) = self.thread.take() {
+            // don't turn a failed test's panic into an abort
+            if thread.join().is_err() && !std::thread::panicking() {
+                panic!("the test server panicked");
+            }
+        }
+        for file in &self.files {
+            let _ = std::fs::remove_file(file);
+        }
+    }
+}
+
+/// A unique path in the temp dir, so parallel tests never share a file.
+fn temp_file(name: &str) -> PathBuf {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let n = COUNT.fetch_add(1, Ordering::Relaxed);
+    let path = std::env::temp_dir().join(format!("chat_test_{name}_{}_{n}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+/// A connection that sends what the test says and reads one message at a time.
+///
+/// Reads fail the test after `READ_TIMEOUT` instead of hanging it.
+pub struct TestClient {
+    socket: TcpStream,
+}
+
+impl TestClient {
+    /// Sends `data` as it is, however it splits frames.
+    pub fn send(&mut self, data: impl AsRef<[u8]>) {
+        self.socket.write_all(data.as_ref()).unwrap();
+    }
+
+    /// Sends `line` as one frame.
+    pub fn send_line(&mut self, line: &str) {
+        self.send(format!("{line}\n"));
+    }
+
+    /// Reads exactly one message, however the server's writes got split or merged.
+    pub fn read_any(&mut self) -> Message {
+        let mut line = vec![];
+        let mut byte = [0u8];
+        while line.last() != Some(&b'\n') {
+            match self.socket.read(&mut byte) {
+                Ok(0) => panic!("the server hung up instead of sending a message"),
+                Ok(_) => line.push(byte[0]),
+                Err(e) => panic!("no message from the server: {e}"),
+            }
+        }
+        Message::decode(String::from_utf8_lossy(&line).trim_end()).unwrap()
+    }
+
+    /// Like `read_any`, but skips the roster updates that arrive whenever anyone logs in or out.
+    pub fn read(&mut self) -> Message {
+        loop {
+            match self.read_any() {
+                Message::Roster { .. } => continue,
+                msg => return msg,
+            }
+        }
+    }
+
+    /// Reads the lobby replay sent right after logging in.
+    pub fn read_history(&mut self) -> Vec<Message> {
+        match self.read() {
+            Message::History { room, messages } if room == DEFAULT_ROOM => messages,
+            other => panic!("expected the lobby history, got {other:?}"),
+        }
+    }
+
+    /// Whether the server hung up with nothing more to say.
+    pub fn closed(&mut self) -> bool {
+        matches!(self.socket.read(&mut [0u8]), Ok(0))
+    }
+
+    /// Our end of the connection, as the server sees it.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.socket.local_addr().unwrap()
+    }
+}
@@ -0,0 +1,28 @@
+use anyhow::{Context, Result};
+use std::{fs::File, io::BufReader, path::Path, sync::Arc};
+use tokio_rustls::{
+    rustls::{self, crypto::ring},
+    TlsAcceptor,
+};
+
+/// Builds a TLS acceptor from a PEM certificate chain and the PEM private key belonging to it.
+pub fn acceptor(cert_path: &Path, key_path: &Path) -> Result<TlsAcceptor> {
+    let mut reader = BufReader::new(
+        File::open(cert_path).with_context(|| format!("can't open {}", cert_path.display()))?,
+    );
+    let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        anyhow::bail!("no certificates in {}", cert_path.display());
+    }
+    let mut reader = BufReader::new(
+        File::open(key_path).with_context(|| format!("can't open {}", key_path.display()))?,
+    );
+    let key = rustls_pemfile::private_key(&mut reader)?
+        .with_context(|| format!("no private key in {}", key_path.display()))?;
+
+    let config = rustls::ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
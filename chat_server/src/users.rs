@@ -1,10 +1,168 @@
-pub fn try_to_login(msg: String) -> Option<String> {
-    let mut iter = msg.split(':');
-    let uname = iter.next()?;
-    let passwd = iter.next()?;
-    if passwd == "123456" && uname.len() < 12 {
-        Some(uname.to_string())
-    } else {
-        None
+use anyhow::Result;
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    path::PathBuf,
+    sync::Mutex,
+};
+
+pub const DEFAULT_USERS_DB: &str = "users.db";
+pub const MAX_USERNAME_LENGTH: usize = 11;
+pub const MIN_PASSWORD_LENGTH: usize = 6;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum LoginError {
+    Malformed,
+    UnknownUser,
+    WrongPassword,
+    InvalidUsername,
+    PasswordTooShort,
+    UserExists,
+    Storage(String),
+}
+
+impl fmt::Display for LoginError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoginError::Malformed => write!(f, "expected [username]:[password]"),
+            LoginError::UnknownUser => write!(f, "no such user"),
+            LoginError::WrongPassword => write!(f, "wrong password"),
+            LoginError::InvalidUsername => write!(
+                f,
+                "usernames are 1-{MAX_USERNAME_LENGTH} letters, digits, '_' or '-'"
+            ),
+            LoginError::PasswordTooShort => write!(
+                f,
+                "passwords need at least {MIN_PASSWORD_LENGTH} characters"
+            ),
+            LoginError::UserExists => write!(f, "username already taken"),
+            LoginError::Storage(e) => write!(f, "couldn't save the account: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for LoginError {}
+
+pub fn validate_username(uname: &str) -> Result<(), LoginError> {
+    let valid_char = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+    if uname.is_empty() || uname.len() > MAX_USERNAME_LENGTH || !uname.chars().all(valid_char) {
+        return Err(LoginError::InvalidUsername);
+    }
+    Ok(())
+}
+
+fn split_credentials(msg: &str) -> Result<(&str, &str), LoginError> {
+    let (uname, passwd) = msg.split_once(':').ok_or(LoginError::Malformed)?;
+    if passwd.contains(':') {
+        return Err(LoginError::Malformed);
+    }
+    Ok((uname, passwd))
+}
+
+/// Accounts stored one per line as `username:argon2-phc-hash`.
+pub struct UserDb {
+    path: PathBuf,
+    users: Mutex<HashMap<String, String>>,
+}
+
+impl UserDb {
+    /// Loads the database from `path`, starting empty if the file doesn't exist yet.
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let mut users = HashMap::new();
+        match fs::read_to_string(&path) {
+            Ok(content) => {
+                for (i, line) in content.lines().enumerate() {
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let (uname, hash) = line.split_once(':').ok_or_else(|| {
+                        anyhow::anyhow!("{}:{}: malformed entry", path.display(), i + 1)
+                    })?;
+                    PasswordHash::new(hash).map_err(|e| {
+                        anyhow::anyhow!("{}:{}: bad password hash: {e}", path.display(), i + 1)
+                    })?;
+                    users.insert(uname.to_string(), hash.to_string());
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        Ok(Self {
+            path,
+            users: Mutex::new(users),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.lock().unwrap().is_empty()
+    }
+
+    pub fn exists(&self, uname: &str) -> bool {
+        self.users.lock().unwrap().contains_key(uname)
+    }
+
+    /// Hashes `passwd` with a fresh salt and appends the account to the database file.
+    pub fn add_user(&self, uname: &str, passwd: &str) -> Result<(), LoginError> {
+        validate_username(uname)?;
+        if passwd.chars().count() < MIN_PASSWORD_LENGTH {
+            return Err(LoginError::PasswordTooShort);
+        }
+        if self.users.lock().unwrap().contains_key(uname) {
+            return Err(LoginError::UserExists);
+        }
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = Argon2::default()
+            .hash_password(passwd.as_bytes(), &salt)
+            .map_err(|e| LoginError::Storage(e.to_string()))?
+            .to_string();
+
+        // check again, someone could have taken the name while we were hashing
+        let mut users = self.users.lock().unwrap();
+        if users.contains_key(uname) {
+            return Err(LoginError::UserExists);
+        }
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| writeln!(file, "{uname}:{hash}"))
+            .map_err(|e| LoginError::Storage(e.to_string()))?;
+        users.insert(uname.to_string(), hash);
+        Ok(())
+    }
+
+    /// Creates an account from a `username:password` line.
+    pub fn register(&self, msg: &str) -> Result<String, LoginError> {
+        let (uname, passwd) = split_credentials(msg)?;
+        self.add_user(uname, passwd)?;
+        Ok(uname.to_string())
+    }
+
+    /// Checks a `username:password` line against the database.
+    pub fn try_to_login(&self, msg: &str) -> Result<String, LoginError> {
+        let (uname, passwd) = split_credentials(msg)?;
+        let hash = self
+            .users
+            .lock()
+            .unwrap()
+            .get(uname)
+            .cloned()
+            .ok_or(LoginError::UnknownUser)?;
+        let hash = PasswordHash::new(&hash).map_err(|_| LoginError::WrongPassword)?;
+        Argon2::default()
+            .verify_password(passwd.as_bytes(), &hash)
+            .map_err(|_| LoginError::WrongPassword)?;
+        Ok(uname.to_string())
     }
 }